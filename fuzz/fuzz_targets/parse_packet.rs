@@ -0,0 +1,10 @@
+#![no_main]
+
+use kalman::message::Packet;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::parse(data) {
+        assert_eq!(Packet::parse(&packet.encode()).ok(), Some(packet));
+    }
+});
@@ -0,0 +1,78 @@
+use kalman::client::DEFAULT_PORT;
+use kalman::simulator::{Simulator, SimulatorConfig};
+
+use clap::Parser;
+use color_print::cprintln;
+
+/// Native IMU sensor stream simulator.
+/// Once the connection is made, send "READY" to start data transmission.
+#[derive(Parser)]
+#[command(version, about, long_about = None, name = "imu_sim")]
+pub(crate) struct Args {
+    /// Manually specify port for the server, falls back to the closest open port
+    #[arg(short, long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+
+    /// Generate seed from unsigned integer, from entropy otherwise
+    #[arg(short, long)]
+    seed: Option<u64>,
+
+    /// Trajectory duration in minutes
+    #[arg(short, long, default_value_t = 90)]
+    duration: u64,
+
+    /// Accelerometer's error sigma
+    #[arg(long, default_value_t = 1e-3)]
+    accsig: f64,
+
+    /// GPS' error sigma
+    #[arg(long, default_value_t = 1e-1)]
+    gpssig: f64,
+
+    /// Multiply the noise applied to the accelerometer and GPS
+    #[arg(short, long, default_value_t = 1.)]
+    noise: f64,
+
+    /// Send true positions in addition to the noised information
+    #[arg(long)]
+    debug: bool,
+
+    /// Print the difference between real and received position at each reception
+    #[arg(long)]
+    delta: bool,
+
+    /// Print the mean filter response time at the end of transmission
+    #[arg(long)]
+    filterspeed: bool,
+
+    /// Send accelerations in the vehicle frame instead of the world frame
+    #[arg(long)]
+    body: bool,
+
+    /// Accelerometer's bias sigma, drifting by as much per sqrt(minute)
+    #[arg(long, default_value_t = 0.)]
+    accbias: f64,
+}
+
+fn main() {
+    let args = Args::parse();
+    let config = SimulatorConfig {
+        port: args.port,
+        seed: args.seed,
+        duration: args.duration,
+        acc_sigma: args.accsig,
+        gps_sigma: args.gpssig,
+        noise: args.noise,
+        debug: args.debug,
+        delta: args.delta,
+        filter_speed: args.filterspeed,
+        body_frame: args.body,
+        acc_bias: args.accbias,
+    };
+
+    let result = Simulator::new(config).and_then(|mut simulator| simulator.run());
+    if let Err(err) = result {
+        cprintln!("<red>{err}</>");
+        std::process::exit(1);
+    }
+}
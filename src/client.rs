@@ -1,56 +1,204 @@
 use crate::error::KalmanError;
-use crate::message::Message;
+use crate::message::{Message, Packet};
+use crate::recorder::{Direction, Recorder};
+use crate::transport::Transport;
 use crate::types::T;
 use std::fmt::Write;
-use std::net::UdpSocket;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
 use std::time::Duration;
 
 pub const MAX_LEN: usize = 1024;
+pub const DEFAULT_PORT: u16 = 4242;
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Wait for an answer before trying the next port, a simulator answers READY
+/// at once
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
 
 #[derive(Debug)]
 pub struct Client {
-    server: &'static str,
+    server: SocketAddr,
+    probe: u16,
     socket: UdpSocket,
     buf: [u8; MAX_LEN],
+    recorder: Option<Recorder>,
 }
 
 impl Client {
-    pub fn new(server_ip: &'static str) -> Result<Client, KalmanError> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
+    /// `probe` is how far from the server port to look for a simulator that
+    /// fell back to the closest open port.
+    pub fn new(server: SocketAddr, probe: u16) -> Result<Client, KalmanError> {
         Ok(Client {
-            server: server_ip,
-            socket,
+            server,
+            probe,
+            socket: bind(server)?,
             buf: [0; MAX_LEN],
+            recorder: None,
         })
     }
 
+    /// Record every datagram received from the server and every position sent.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Client {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Sends READY to the server port and, while it stays quiet for
+    /// `PROBE_TIMEOUT`, to the next neighbour found listening. READY goes to
+    /// no other port, and the first to answer with a protocol packet becomes
+    /// the server.
     pub fn start(&mut self) -> Result<(), KalmanError> {
-        self.socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut neighbours = self.neighbours().into_iter();
+        let mut readied = vec![self.server];
         self.socket.send_to(b"READY", self.server)?;
-        loop {
+        let mut packet = loop {
+            let timeout = if neighbours.as_slice().is_empty() {
+                TIMEOUT
+            } else {
+                PROBE_TIMEOUT
+            };
+            self.socket.set_read_timeout(Some(timeout))?;
             println!("Connection ...");
-            match self.recv_into_buf() {
-                Ok(Message::Generation) => break,
-                Ok(msg) => println!("Received: {}", msg),
-                Err(_) => continue,
+            if let Ok((len, from)) = self.socket.recv_from(&mut self.buf)
+                && readied.contains(&from)
+                && let Ok(packet) = self.parse(len)
+            {
+                self.server = from;
+                break packet;
+            }
+            if let Some(neighbour) = neighbours.find(|addr| listening(*addr)) {
+                self.socket.send_to(b"READY", neighbour)?;
+                readied.push(neighbour);
             }
+        };
+        self.socket.set_read_timeout(Some(TIMEOUT))?;
+        while packet.message != Message::Generation {
+            println!("Received: {}", packet.message);
+            packet = loop {
+                println!("Connection ...");
+                if let Ok(packet) = self.recv_into_buf() {
+                    break packet;
+                }
+            };
         }
-        println!("Connected !");
+        println!("Connected to {} !", self.server);
         Ok(())
     }
 
-    pub fn recv_into_buf(&mut self) -> Result<Message, KalmanError> {
-        match self.socket.recv_from(&mut self.buf) {
-            Ok((len, _)) if len >= MAX_LEN => Err(KalmanError::MessageTooLong(len)),
-            Ok((len, _)) => Message::try_from(str::from_utf8(&self.buf[..len])?),
-            Err(e) => Err(KalmanError::Io(e)),
+    /// Ports up to `probe` away from the server one, by increasing distance
+    fn neighbours(&self) -> Vec<SocketAddr> {
+        let port = self.server.port();
+        let mut neighbours = Vec::new();
+        for offset in 1..=self.probe {
+            for candidate in [port.checked_add(offset), port.checked_sub(offset)]
+                .into_iter()
+                .flatten()
+            {
+                neighbours.push(SocketAddr::new(self.server.ip(), candidate));
+            }
         }
+        neighbours
     }
 
-    pub fn send_position(&self, pos: &[T]) -> Result<(), KalmanError> {
+    pub fn recv_into_buf(&mut self) -> Result<Packet, KalmanError> {
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((_, from)) if from != self.server => continue,
+                Ok((len, _)) => return self.parse(len),
+                Err(e) => return Err(KalmanError::Io(e)),
+            }
+        }
+    }
+
+    fn parse(&mut self, len: usize) -> Result<Packet, KalmanError> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(Direction::Received, &self.buf[..len])?;
+        }
+        if len >= MAX_LEN {
+            return Err(KalmanError::MessageTooLong(len));
+        }
+        Packet::parse(&self.buf[..len])
+    }
+
+    pub fn send_position(&mut self, pos: &[T]) -> Result<(), KalmanError> {
         let mut msg = String::with_capacity(64);
         write!(&mut msg, "{} {} {}", pos[0], pos[1], pos[2])?;
         self.socket.send_to(msg.as_bytes(), self.server)?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(Direction::Sent, msg.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the buffered end of the recording, if any
+    pub fn flush(&mut self) -> Result<(), KalmanError> {
+        match &mut self.recorder {
+            Some(recorder) => recorder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Ephemeral socket of the address family of `server`
+fn bind(server: SocketAddr) -> io::Result<UdpSocket> {
+    match server {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0"),
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0"),
+    }
+}
+
+/// Whether a socket is bound to `addr`. An empty datagram, which no protocol
+/// message is, comes back as refused from a closed port.
+fn listening(addr: SocketAddr) -> bool {
+    let probe = || -> io::Result<bool> {
+        let socket = bind(addr)?;
+        socket.connect(addr)?;
+        socket.send(&[])?;
+        socket.set_read_timeout(Some(PROBE_TIMEOUT))?;
+        match socket.recv(&mut [0; 1]) {
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => Ok(false),
+            _ => Ok(true),
+        }
+    };
+    probe().unwrap_or(false)
+}
+
+impl Transport for Client {
+    fn start(&mut self) -> Result<(), KalmanError> {
+        Client::start(self)
+    }
+
+    fn recv(&mut self) -> Result<Packet, KalmanError> {
+        self.recv_into_buf()
+    }
+
+    fn send_position(&mut self, pos: &[T]) -> Result<(), KalmanError> {
+        Client::send_position(self, pos)
+    }
+
+    fn flush(&mut self) -> Result<(), KalmanError> {
+        Client::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_without_sending_ready() -> Result<(), KalmanError> {
+        let open = UdpSocket::bind("127.0.0.1:0")?;
+        let closed = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+        assert!(listening(open.local_addr()?));
+        assert!(!listening(closed));
+
+        let mut buf = [0; MAX_LEN];
+        let (len, _) = open.recv_from(&mut buf)?;
+        assert_eq!(len, 0);
         Ok(())
     }
 }
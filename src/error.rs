@@ -2,6 +2,8 @@ use std::fmt::Error as FmtError;
 use std::num::ParseFloatError;
 use thiserror::Error;
 
+use crate::types::T;
+
 #[derive(Debug, Error)]
 pub enum KalmanError {
     #[error("Communication error: {0}")]
@@ -16,8 +18,39 @@ pub enum KalmanError {
     Fmt(#[from] FmtError),
     #[error("Parsing error: {0}")]
     Parsing(String),
+    #[error("Unknown message: {raw}")]
+    UnknownMessage { raw: String },
+    #[error("Invalid header: {raw}")]
+    InvalidHeader { raw: String },
+    #[error("{message}: missing {field} in {raw}")]
+    MissingField {
+        message: &'static str,
+        field: &'static str,
+        raw: String,
+    },
+    #[error("{message}: invalid {field} \"{value}\" in {raw}")]
+    InvalidField {
+        message: &'static str,
+        field: &'static str,
+        value: String,
+        raw: String,
+    },
+    #[error("{message}: trailing data in {raw}")]
+    TrailingData { message: &'static str, raw: String },
     #[error("Inversion error")]
     Inversion(String),
+    #[error("{0} is not positive definite")]
+    NotPositiveDefinite(&'static str),
+    #[error("Position receipt timed out")]
+    TimeoutPosReceipt,
+    #[error("Incorrect data input format: {0}")]
+    IncorrectDataInput(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Channel disconnected")]
+    Disconnected,
+    #[error("Delta is too high: {0}")]
+    DeltaTooHigh(T),
 }
 
 impl From<&'static str> for KalmanError {
@@ -0,0 +1,55 @@
+use crate::error::KalmanError;
+use crate::kalman::gate::Gate;
+use crate::kalman::rts::{History, Lagged};
+use crate::plot_data::PlotData;
+use crate::types::{T, Vector3};
+
+/// What the orchestrator needs from a filter. The state starts with the
+/// position and velocity, whatever else the filter estimates.
+pub trait Estimator {
+    /// First block: true position, speed in km/h and Euler direction
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3);
+    /// ACCELERATION, `dt` being the time since the previous one when known
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError>;
+    /// POSITION
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError>;
+
+    /// SPEED in km/h, ignored by default
+    fn correction_speed(&mut self, _speed: T) -> Result<(), KalmanError> {
+        Ok(())
+    }
+
+    /// DIRECTION as Euler angles, ignored by default
+    fn correction_direction(&mut self, _dir: &Vector3) -> Result<(), KalmanError> {
+        Ok(())
+    }
+
+    /// Whether DIRECTION is part of the model rather than an optional aid
+    fn needs_direction(&self) -> bool {
+        false
+    }
+
+    fn get_state(&self) -> &[T];
+    fn get_state_variance(&self) -> &[T; 6];
+    fn get_innovation(&self) -> &[T];
+    fn get_nis(&self) -> T;
+
+    /// Outlier rejection of the POSITION fixes, if the filter has one
+    fn gate(&self) -> Option<&Gate> {
+        None
+    }
+
+    /// Steps kept for smoothing, if the filter records them
+    fn history(&self) -> Option<&History> {
+        None
+    }
+
+    /// Refined estimate of the step a fixed lag behind, if the filter keeps
+    /// one
+    fn lagged_state(&self) -> Result<Option<Lagged>, KalmanError> {
+        Ok(None)
+    }
+
+    /// Series specific to the filter, after the common ones are pushed
+    fn push_plot_data(&self, _plot_data: &mut PlotData) {}
+}
@@ -12,6 +12,9 @@ const POSITION_LABEL: &str = "Position";
 const SPEED_LABEL: &str = "Speed";
 const UNCERTAINTIES_LABEL: &str = "Uncertainty";
 const INNOVATION_LABEL: &str = "Innovation";
+const BIAS_LABEL: &str = "Bias";
+const LAG_LABEL: &str = "Fixed lag";
+const MODELS_LABEL: &str = "Models";
 
 pub struct GuiView {
     plot_data: Arc<Mutex<PlotData>>,
@@ -86,6 +89,9 @@ impl GuiView {
                     Self::render_speed_panel(ui, &plot_ctx.get_plot_ui(ui), plot_data);
                     Self::render_variance_panel(ui, &plot_ctx.get_plot_ui(ui), plot_data);
                     Self::render_innov_panel(ui, &plot_ctx.get_plot_ui(ui), plot_data);
+                    Self::render_bias_panel(ui, &plot_ctx.get_plot_ui(ui), plot_data);
+                    Self::render_lag_panel(ui, &plot_ctx.get_plot_ui(ui), plot_data);
+                    Self::render_models_panel(ui, &plot_ctx.get_plot_ui(ui), plot_data);
                 };
             });
     }
@@ -115,9 +121,12 @@ impl GuiView {
 
         DockBuilder::dock_window(TRAJECTORY_LABEL, left_id);
         DockBuilder::dock_window(POSITION_LABEL, right_1_id);
+        DockBuilder::dock_window(LAG_LABEL, right_1_id);
         DockBuilder::dock_window(SPEED_LABEL, right_2_id);
         DockBuilder::dock_window(UNCERTAINTIES_LABEL, right_3_id);
+        DockBuilder::dock_window(BIAS_LABEL, right_3_id);
         DockBuilder::dock_window(INNOVATION_LABEL, right_4_id);
+        DockBuilder::dock_window(MODELS_LABEL, right_4_id);
 
         DockBuilder::finish(dockspace_id);
     }
@@ -219,6 +228,91 @@ impl GuiView {
         });
     }
 
+    /// Only shown when the filter estimates the accelerometer bias
+    fn render_bias_panel(ui: &Ui, plot_ui: &PlotUi, plot_data: &Arc<Mutex<PlotData>>) {
+        use dear_implot::*;
+        if plot_data.lock().map_or(true, |plot_data| plot_data.bx.is_empty()) {
+            return;
+        }
+        let flags = WindowFlags::NO_DECORATION | WindowFlags::NO_NAV | WindowFlags::NO_MOVE;
+        ui.window(BIAS_LABEL).flags(flags).build(|| {
+            let Some(plot) = plot_ui.begin_plot_with_size(BIAS_LABEL, [-1., -1.]) else {
+                return;
+            };
+            let flags = AxisFlags::AUTO_FIT;
+            plot_ui.setup_x_axis(XAxis::X1, Some("time"), flags);
+            plot_ui.setup_y_axis(YAxis::Y1, Some("bias (m/s2)"), flags);
+            plot_ui.setup_y_axis(YAxis::Y2, Some("bias var"), flags);
+            if let Ok(plot_data) = plot_data.lock() {
+                plot_ui.set_axes(XAxis::X1, YAxis::Y1);
+                SimpleLinePlot::new("X", &plot_data.bx).plot();
+                SimpleLinePlot::new("Y", &plot_data.by).plot();
+                SimpleLinePlot::new("Z", &plot_data.bz).plot();
+
+                plot_ui.set_axes(XAxis::X1, YAxis::Y2);
+                SimpleLinePlot::new("X var", &plot_data.bx_unc).plot();
+                SimpleLinePlot::new("Y var", &plot_data.by_unc).plot();
+                SimpleLinePlot::new("Z var", &plot_data.bz_unc).plot();
+            }
+            plot.end();
+        });
+    }
+
+    /// Only shown when the filter keeps a fixed lag
+    fn render_lag_panel(ui: &Ui, plot_ui: &PlotUi, plot_data: &Arc<Mutex<PlotData>>) {
+        use dear_implot::*;
+        if plot_data.lock().map_or(true, |plot_data| plot_data.x_lag.is_empty()) {
+            return;
+        }
+        let flags = WindowFlags::NO_DECORATION | WindowFlags::NO_NAV | WindowFlags::NO_MOVE;
+        ui.window(LAG_LABEL).flags(flags).build(|| {
+            let Some(plot) = plot_ui.begin_plot_with_size(LAG_LABEL, [-1., -1.]) else {
+                return;
+            };
+            let flags = AxisFlags::AUTO_FIT;
+            plot_ui.setup_x_axis(XAxis::X1, Some("time"), flags);
+            plot_ui.setup_y_axis(YAxis::Y1, Some("x (m)"), flags);
+            plot_ui.setup_y_axis(YAxis::Y2, Some("y (m)"), flags);
+            plot_ui.setup_y_axis(YAxis::Y3, Some("z (m)"), flags);
+            if let Ok(plot_data) = plot_data.lock() {
+                plot_ui.set_axes(XAxis::X1, YAxis::Y1);
+                SimpleLinePlot::new("X smoothed", &plot_data.x_lag).plot();
+                SimpleLinePlot::new("X real-time", &plot_data.x_lag_rt).plot();
+                plot_ui.set_axes(XAxis::X1, YAxis::Y2);
+                SimpleLinePlot::new("Y smoothed", &plot_data.y_lag).plot();
+                SimpleLinePlot::new("Y real-time", &plot_data.y_lag_rt).plot();
+                plot_ui.set_axes(XAxis::X1, YAxis::Y3);
+                SimpleLinePlot::new("Z smoothed", &plot_data.z_lag).plot();
+                SimpleLinePlot::new("Z real-time", &plot_data.z_lag_rt).plot();
+            }
+            plot.end();
+        });
+    }
+
+    /// Only shown when the filter mixes several models
+    fn render_models_panel(ui: &Ui, plot_ui: &PlotUi, plot_data: &Arc<Mutex<PlotData>>) {
+        use dear_implot::*;
+        if plot_data.lock().map_or(true, |plot_data| plot_data.model_names.is_empty()) {
+            return;
+        }
+        let flags = WindowFlags::NO_DECORATION | WindowFlags::NO_NAV | WindowFlags::NO_MOVE;
+        ui.window(MODELS_LABEL).flags(flags).build(|| {
+            let Some(plot) = plot_ui.begin_plot_with_size(MODELS_LABEL, [-1., -1.]) else {
+                return;
+            };
+            let flags = AxisFlags::AUTO_FIT;
+            plot_ui.setup_x_axis(XAxis::X1, Some("time"), flags);
+            plot_ui.setup_y_axis(YAxis::Y1, Some("probability"), flags);
+            if let Ok(plot_data) = plot_data.lock() {
+                plot_ui.set_axes(XAxis::X1, YAxis::Y1);
+                for (name, probs) in plot_data.model_names.iter().zip(&plot_data.model_probs) {
+                    SimpleLinePlot::new(name, probs).plot();
+                }
+            }
+            plot.end();
+        });
+    }
+
     fn draw_trajectory_panel(
         ui: &Ui,
         plot3d_ctx: &Plot3DContext,
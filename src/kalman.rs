@@ -1,30 +1,30 @@
 use crate::{
     error::KalmanError,
-    types::{Matrix3, Matrix3x6, Matrix6, Matrix6x3, Vector3, Vector6, T},
+    estimator::Estimator,
+    types::{Matrix3, Matrix3x6, Matrix6, Matrix6x3, T, Vector3, Vector6},
 };
-use nalgebra::{matrix, vector, Rotation3};
+use nalgebra::{Cholesky, Const, Matrix1, Matrix2, Rotation3, SMatrix, SVector, matrix, vector};
+use std::f64::consts::PI;
 
-const DT: T = 0.01;
-const MULT: T = 50. * 2.5;
-const S_ACC: T = 1e-3 * MULT;
-const S_GYR: T = 1e-2 * MULT;
-const S_GPS: T = 1e-1 * MULT;
+pub mod accel;
+pub mod adaptive;
+pub mod bias;
+pub mod config;
+pub mod gate;
+pub mod imm;
+pub mod ins;
+pub mod particle;
+pub mod rts;
+pub mod ukf;
 
-const S_ACC2: T = S_ACC * S_ACC;
-const S_GYR2: T = S_GYR * S_GYR;
-const S_GPS2: T = S_GPS * S_GPS;
+use adaptive::AdaptiveNoise;
+pub use config::KalmanConfig;
+use gate::Gate;
+use rts::{History, Lagged};
 
-const DD: T = 0.5 * DT * DT;
-const KMH_TO_MS: T = 1000. / 3600.;
+const MIN_SPEED: T = 1e-1; // below it the heading of the velocity is meaningless
 
-const A: Matrix6 = matrix![
-    1.,0.,0.,DT,0.,0.;
-    0.,1.,0.,0.,DT,0.;
-    0.,0.,1.,0.,0.,DT;
-    0.,0.,0.,1.,0.,0.;
-    0.,0.,0.,0.,1.,0.;
-    0.,0.,0.,0.,0.,1.
-];
+const KMH_TO_MS: T = 1000. / 3600.;
 
 const H: Matrix3x6 = matrix![
     1.,0.,0.,0.,0.,0.;
@@ -32,30 +32,6 @@ const H: Matrix3x6 = matrix![
     0.,0.,1.,0.,0.,0.;
 ];
 
-const B: Matrix6x3 = matrix![
-    DD,0.,0.;
-    0.,DD,0.;
-    0.,0.,DD;
-    DT,0.,0.;
-    0.,DT,0.;
-    0.,0.,DT;
-];
-
-const P: Matrix6 = matrix![
-    S_GPS2, 0., 0., 0., 0., 0.;
-    0., S_GPS2, 0., 0., 0., 0.;
-    0., 0., S_GPS2, 0., 0., 0.;
-    0., 0., 0., S_GYR2 + S_ACC2 * DT, 0., 0.;
-    0., 0., 0., 0., S_GYR2 + S_ACC2 * DT, 0. ;
-    0., 0., 0., 0., 0., S_GYR2 + S_ACC2 * DT
-];
-
-const R: Matrix3 = matrix![
-    S_GPS2, 0., 0.;
-    0., S_GPS2, 0.;
-    0., 0., S_GPS2
-];
-
 #[derive(Default, Debug)]
 pub struct Kalman {
     x: Vector6, // State
@@ -71,65 +47,366 @@ pub struct Kalman {
     b: Matrix6x3, // Control-input
     innovation: Vector3,
     nis: T,
+    config: KalmanConfig,
+    dt: T, // Step of a, b and q
+    adaptive: AdaptiveNoise,
+    gate: Gate,
+    history: Option<History>, // Steps kept for smoothing
+    window: Option<History>,  // Last steps, for the fixed-lag estimate
 }
 impl Kalman {
-    pub fn new() -> Kalman {
-        let q = B * B.transpose() * S_ACC2;
-        Kalman {
+    pub fn new(config: KalmanConfig) -> Kalman {
+        let mut filter = Kalman {
             x: Vector6::zeros(),
-            p: P,
+            p: config.p(),
             k: Matrix6x3::zeros(),
-            a: A,
-            a_t: A.transpose(),
             h: H,
             h_t: H.transpose(),
-            q,
-            r: R,
-            b: B,
+            r: config.r(),
+            config,
+            adaptive: AdaptiveNoise::new(config.adaptive, config.r()),
+            gate: Gate::new(config.gate, 3),
             ..Default::default()
+        };
+        filter.set_time_step(config.dt);
+        filter
+    }
+
+    fn set_time_step(&mut self, dt: T) {
+        self.dt = dt;
+        self.a = self.config.a(dt);
+        self.a_t = self.a.transpose();
+        self.b = self.config.b(dt);
+        self.q = self.config.q(dt) * self.adaptive.q_scale();
+    }
+
+    fn corrected(&mut self) {
+        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        for history in self.history.iter_mut().chain(&mut self.window) {
+            history.corrected(&self.x, &self.p);
         }
     }
 
-    pub fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+    /// Keep every step for `History::smooth`
+    pub fn with_history(mut self) -> Kalman {
+        self.history = Some(History::new(self.config));
+        self
+    }
+
+    /// Also estimate each step `lag` steps later, see `History::lagged`
+    pub fn with_fixed_lag(mut self, lag: usize) -> Kalman {
+        self.window = Some(History::window(self.config, lag));
+        self
+    }
+}
+
+impl Estimator for Kalman {
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
         let rot = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
         let v0 = rot * vector![speed * KMH_TO_MS, 0., 0.];
         self.x = vector!(pos.x, pos.y, pos.z, v0.x, v0.y, v0.z,);
+        for history in self.history.iter_mut().chain(&mut self.window) {
+            history.start(&self.x, &self.p);
+        }
     }
 
-    pub fn prediction(&mut self, acc: &Vector3) -> Result<(), KalmanError> {
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.set_time_step(dt);
+        }
         self.x = self.a * self.x + self.b * acc;
         self.p = self.a * self.p * self.a_t + self.q;
         self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        for history in self.history.iter_mut().chain(&mut self.window) {
+            history.predicted(dt, &self.x, &self.p)?;
+        }
         Ok(())
     }
 
-    pub fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
-        let mut tmp = self.h * self.p * self.h_t + self.r;
-        tmp = tmp.pseudo_inverse(1e-5)?;
-        self.k = self.p * self.h_t * tmp;
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        let hph_prior = self.h * self.p * self.h_t;
         self.innovation = z - self.h * self.x;
-        self.nis = (self.innovation.transpose()
-            * (self.h * self.p * self.h_t + self.r).pseudo_inverse(1e-5)?
-            * self.innovation)
-            .x;
+        let s = innovation_covariance(&self.p, &self.h, &self.r)?;
+        self.nis = nis(&s, &self.innovation);
+        if !self.gate.accept(self.nis) {
+            return Ok(());
+        }
+        self.k = joseph_update(&mut self.p, &self.h, &self.r, &s);
         self.x += self.k * self.innovation;
-        self.p -= self.k * self.h * self.p;
-        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        self.corrected();
+
+        let residual = z - self.h * self.x;
+        let hph_post = self.h * self.p * self.h_t;
+        if self
+            .adaptive
+            .observe(&self.innovation, &hph_prior, &residual, &hph_post)
+        {
+            self.set_time_step(self.dt);
+        }
+        self.r = *self.adaptive.r();
+        Ok(())
+    }
+
+    /// Speed measurement, `speed` in km/h: z = |v|.
+    fn correction_speed(&mut self, speed: T) -> Result<(), KalmanError> {
+        correct_speed(&mut self.x, &mut self.p, speed, self.config.s_speed)?;
+        self.corrected();
         Ok(())
     }
 
-    pub fn get_state(&self) -> &[T] {
+    /// Heading measurement from a DIRECTION (roll, pitch, yaw): z = (pitch,
+    /// yaw) of the velocity. Roll says nothing about the velocity.
+    fn correction_direction(&mut self, dir: &Vector3) -> Result<(), KalmanError> {
+        correct_direction(&mut self.x, &mut self.p, dir, self.config.s_heading)?;
+        self.corrected();
+        Ok(())
+    }
+
+    fn get_state(&self) -> &[T] {
         self.x.as_slice()
     }
 
-    pub fn get_state_variance(&self) -> &[T; 6] {
+    fn get_state_variance(&self) -> &[T; 6] {
         &self.p_diag
     }
 
-    pub fn get_innovation(&self) -> &[T] {
+    fn get_innovation(&self) -> &[T] {
         self.innovation.as_slice()
     }
-    pub fn get_nis(&self) -> T {
+
+    fn get_nis(&self) -> T {
         self.nis
     }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+
+    fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    fn lagged_state(&self) -> Result<Option<Lagged>, KalmanError> {
+        match &self.window {
+            Some(window) => window.lagged(),
+            None => Ok(None),
+        }
+    }
+}
+
+fn wrap_angle(angle: T) -> T {
+    (angle + PI).rem_euclid(2. * PI) - PI
+}
+
+/// `correction_speed` for any state holding the velocity in rows 3 to 5,
+/// `sigma` in m/s
+fn correct_speed<const N: usize>(
+    x: &mut SVector<T, N>,
+    p: &mut SMatrix<T, N, N>,
+    speed: T,
+    sigma: T,
+) -> Result<(), KalmanError> {
+    let v = x.fixed_rows::<3>(3).into_owned();
+    let norm = v.norm();
+    if norm < MIN_SPEED {
+        return Ok(());
+    }
+    let mut h = SMatrix::<T, 1, N>::zeros();
+    h.fixed_columns_mut::<3>(3).tr_copy_from(&(v / norm));
+    let innovation = vector![speed * KMH_TO_MS - norm];
+    update(x, p, &h, innovation, &Matrix1::new(sigma * sigma))
+}
+
+/// `correction_direction` for any state holding the velocity in rows 3 to 5,
+/// `sigma` in rad
+fn correct_direction<const N: usize>(
+    x: &mut SVector<T, N>,
+    p: &mut SMatrix<T, N, N>,
+    dir: &Vector3,
+    sigma: T,
+) -> Result<(), KalmanError> {
+    let v = x.fixed_rows::<3>(3).into_owned();
+    let horizontal = v.x.hypot(v.y);
+    if horizontal < MIN_SPEED {
+        return Ok(());
+    }
+    let norm2 = v.norm_squared();
+    let h2 = horizontal * horizontal;
+    // Jacobians of pitch = atan2(-vz, horizontal) and yaw = atan2(vy, vx)
+    let mut h = SMatrix::<T, 2, N>::zeros();
+    h[(0, 3)] = v.x * v.z / (horizontal * norm2);
+    h[(0, 4)] = v.y * v.z / (horizontal * norm2);
+    h[(0, 5)] = -horizontal / norm2;
+    h[(1, 3)] = -v.y / h2;
+    h[(1, 4)] = v.x / h2;
+    let innovation = vector![
+        wrap_angle(dir[1] - (-v.z).atan2(horizontal)),
+        wrap_angle(dir[2] - v.y.atan2(v.x))
+    ];
+    let r = Matrix2::from_diagonal_element(sigma * sigma);
+    update(x, p, &h, innovation, &r)
+}
+
+/// Measurement update for a linearised measurement matrix `h`
+fn update<const N: usize, const M: usize>(
+    x: &mut SVector<T, N>,
+    p: &mut SMatrix<T, N, N>,
+    h: &SMatrix<T, M, N>,
+    innovation: SVector<T, M>,
+    r: &SMatrix<T, M, M>,
+) -> Result<(), KalmanError> {
+    let s = innovation_covariance(p, h, r)?;
+    *x += joseph_update(p, h, r, &s) * innovation;
+    Ok(())
+}
+
+/// Cholesky factor of `H P H' + R`, shared by the gain and the NIS
+fn innovation_covariance<const N: usize, const M: usize>(
+    p: &SMatrix<T, N, N>,
+    h: &SMatrix<T, M, N>,
+    r: &SMatrix<T, M, M>,
+) -> Result<Cholesky<T, Const<M>>, KalmanError> {
+    symmetrize(h * p * h.transpose() + r)
+        .cholesky()
+        .ok_or(KalmanError::NotPositiveDefinite("Innovation covariance"))
+}
+
+fn nis<const M: usize>(s: &Cholesky<T, Const<M>>, innovation: &SVector<T, M>) -> T {
+    innovation.dot(&s.solve(innovation))
+}
+
+/// Returns the gain. The Joseph form keeps `p` symmetric positive definite
+/// whatever the rounding of the gain.
+fn joseph_update<const N: usize, const M: usize>(
+    p: &mut SMatrix<T, N, N>,
+    h: &SMatrix<T, M, N>,
+    r: &SMatrix<T, M, M>,
+    s: &Cholesky<T, Const<M>>,
+) -> SMatrix<T, N, M> {
+    // K = P H' S^-1, solved as S K' = H P
+    let k = s.solve(&(h * *p)).transpose();
+    let i_kh = SMatrix::<T, N, N>::identity() - k * h;
+    *p = symmetrize(i_kh * *p * i_kh.transpose() + k * r * k.transpose());
+    k
+}
+
+fn symmetrize<const N: usize>(m: SMatrix<T, N, N>) -> SMatrix<T, N, N> {
+    (m + m.transpose()) * 0.5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use adaptive::Adapt;
+
+    /// Well conditioned, correlated position and velocity
+    fn covariance() -> Matrix6 {
+        let l = Matrix6::from_fn(|i, j| if j <= i { 1. / (1 + i + j) as T } else { 0. });
+        l * l.transpose() + Matrix6::identity() * 0.1
+    }
+
+    #[test]
+    fn joseph_matches_the_simple_form() -> Result<(), KalmanError> {
+        let r = Matrix3::identity() * 0.04;
+        let mut p = covariance();
+        let s = innovation_covariance(&p, &H, &r)?;
+        let gain = p * H.transpose() * (H * p * H.transpose() + r).try_inverse().unwrap();
+        let simple = (Matrix6::identity() - gain * H) * p;
+        let k = joseph_update(&mut p, &H, &r, &s);
+        assert!((k - gain).abs().max() < 1e-12);
+        assert!((p - simple).abs().max() < 1e-12);
+        Ok(())
+    }
+
+    /// Fix after fix of a much better sensor than the state
+    #[test]
+    fn joseph_stays_symmetric_positive() -> Result<(), KalmanError> {
+        let config = KalmanConfig::default();
+        let (a, q) = (config.a(config.dt), config.q(config.dt));
+        let r = Matrix3::identity() * 1e-10;
+        let mut p = covariance() * 1e4;
+        for _ in 0..1000 {
+            p = a * p * a.transpose() + q;
+            let s = innovation_covariance(&p, &H, &r)?;
+            joseph_update(&mut p, &H, &r, &s);
+            assert_eq!(p, p.transpose());
+            assert!(p.symmetric_eigenvalues().min() > 0., "{p}");
+        }
+        Ok(())
+    }
+
+    /// Along x at `speed` m/s, unit covariance
+    fn moving(speed: T) -> (Vector6, Matrix6) {
+        (vector![0., 0., 0., speed, 0., 0.], Matrix6::identity())
+    }
+
+    #[test]
+    fn speed_update_moves_toward_the_measurement() -> Result<(), KalmanError> {
+        let (mut x, mut p) = moving(10.);
+        correct_speed(&mut x, &mut p, 12. / KMH_TO_MS, 0.1)?;
+        let speed = x.fixed_rows::<3>(3).norm();
+        assert!(speed > 11. && speed < 12., "{speed}");
+        assert!(p[(3, 3)] < 1.);
+        Ok(())
+    }
+
+    #[test]
+    fn direction_update_turns_the_velocity_toward_the_heading() -> Result<(), KalmanError> {
+        let (mut x, mut p) = moving(10.);
+        correct_direction(&mut x, &mut p, &vector![0., -0.1, 0.3], 0.01)?;
+        let yaw = x[4].atan2(x[3]);
+        let pitch = (-x[5]).atan2(x[3].hypot(x[4]));
+        assert!(yaw > 0.25 && yaw < 0.3, "{yaw}");
+        assert!(pitch < -0.05 && pitch > -0.1, "{pitch}");
+        Ok(())
+    }
+
+    #[test]
+    fn velocity_updates_are_skipped_below_min_speed() -> Result<(), KalmanError> {
+        let (mut x, mut p) = moving(MIN_SPEED / 2.);
+        let before = (x, p);
+        correct_speed(&mut x, &mut p, 36., 0.1)?;
+        correct_direction(&mut x, &mut p, &vector![0., 0., 1.], 0.01)?;
+        assert_eq!((x, p), before);
+        Ok(())
+    }
+
+    /// GPS fixes of 1 m sigma around a still vehicle, `R` being configured
+    /// for 12.5 m
+    fn adapted_r(mode: Adapt) -> Result<Matrix3, KalmanError> {
+        let mut config = KalmanConfig::default();
+        config.adaptive.mode = mode;
+        let mut filter = Kalman::new(config);
+        let mut rng = Rng::new(1);
+        filter.init(Vector3::zeros(), 0., Vector3::zeros());
+        for _ in 0..100 {
+            filter.prediction(&Vector3::zeros(), None)?;
+            filter.correction(&Vector3::from_fn(|_, _| rng.normal(1.)))?;
+        }
+        Ok(filter.r)
+    }
+
+    #[test]
+    fn adapt_r_estimates_the_gps_noise() -> Result<(), KalmanError> {
+        let configured = KalmanConfig::default().r();
+        assert_eq!(adapted_r(Adapt::Off)?, configured);
+        let adapted = adapted_r(Adapt::R)?;
+        for v in adapted.diagonal().iter() {
+            assert!(*v > 0.25 && *v < 4., "{v}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn non_positive_innovation_covariance_is_an_error() {
+        let mut filter = Kalman::new(KalmanConfig::default());
+        filter.r = -filter.r * 2.;
+        let result = filter.correction(&Vector3::zeros());
+        assert!(matches!(
+            result,
+            Err(KalmanError::NotPositiveDefinite("Innovation covariance"))
+        ));
+    }
 }
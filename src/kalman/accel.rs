@@ -0,0 +1,383 @@
+//! Linear filter with the acceleration as a state.
+//!
+//! State is position, velocity and acceleration, moved by a constant
+//! acceleration model (white jerk) or the Singer model, whose acceleration
+//! decays towards zero as a first order Gauss-Markov process. ACCELERATION
+//! is then a noisy measurement of the acceleration state rather than a
+//! noiseless input. Both models are discretised exactly, by Van Loan's
+//! method on each axis.
+
+use super::gate::Gate;
+use super::{
+    KMH_TO_MS, KalmanConfig, correct_direction, correct_speed, innovation_covariance,
+    joseph_update, nis, symmetrize, update,
+};
+use crate::{
+    error::KalmanError,
+    estimator::Estimator,
+    types::{Matrix3, T, Vector3},
+};
+use nalgebra::{Rotation3, SMatrix, SVector, vector};
+use std::fmt;
+use std::str::FromStr;
+
+const N: usize = 9;
+type Matrix9 = SMatrix<T, N, N>;
+type Matrix3x9 = SMatrix<T, 3, N>;
+type Vector9 = SVector<T, N>;
+
+const ACC: usize = 6;
+
+/// Motion model `AccelKalman::new` picks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccelModel {
+    /// `constant`, the acceleration drifting by a white jerk
+    #[default]
+    Constant,
+    /// `singer`, the acceleration pulled back to zero over a time constant
+    Singer,
+}
+
+impl FromStr for AccelModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(AccelModel::Constant),
+            "singer" => Ok(AccelModel::Singer),
+            _ => Err(format!("expected constant or singer, got {s}")),
+        }
+    }
+}
+
+impl fmt::Display for AccelModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AccelModel::Constant => "constant",
+            AccelModel::Singer => "singer",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccelConfig {
+    pub model: AccelModel,
+    /// White jerk of the constant acceleration model, m/s^3 per sqrt(Hz)
+    pub jerk: T,
+    /// Correlation time of the Singer acceleration, s
+    pub tau: T,
+    /// Steady state sigma of the Singer acceleration, m/s^2
+    pub sigma: T,
+}
+
+impl Default for AccelConfig {
+    fn default() -> Self {
+        AccelConfig {
+            model: AccelModel::Constant,
+            jerk: 1.,
+            tau: 2.,
+            sigma: 1.,
+        }
+    }
+}
+
+impl AccelConfig {
+    /// Inverse correlation time and white noise density driving the
+    /// acceleration, the constant model being the Singer one without decay
+    fn dynamics(&self) -> (T, T) {
+        match self.model {
+            AccelModel::Constant => (0., self.jerk * self.jerk),
+            AccelModel::Singer => (1. / self.tau, 2. * self.sigma * self.sigma / self.tau),
+        }
+    }
+
+    /// State transition and process noise over `dt` of the position,
+    /// velocity and acceleration of all three axes
+    fn discretize(&self, dt: T) -> (Matrix9, Matrix9) {
+        let (alpha, density) = self.dynamics();
+        // exp([-F, G q G'; 0, F'] dt) = [., A^-1 Q; 0, A']
+        let mut m = SMatrix::<T, 6, 6>::zeros();
+        m[(0, 1)] = -dt;
+        m[(1, 2)] = -dt;
+        m[(2, 2)] = alpha * dt;
+        m[(2, 5)] = density * dt;
+        m[(4, 3)] = dt;
+        m[(5, 4)] = dt;
+        m[(5, 5)] = -alpha * dt;
+        let exp = m.exp();
+        let a = exp.fixed_view::<3, 3>(3, 3).transpose();
+        let q = symmetrize(a * exp.fixed_view::<3, 3>(0, 3));
+
+        // Rows of an axis are its position, velocity and acceleration
+        let (mut a9, mut q9) = (Matrix9::zeros(), Matrix9::zeros());
+        for axis in 0..3 {
+            for i in 0..3 {
+                for j in 0..3 {
+                    a9[(3 * i + axis, 3 * j + axis)] = a[(i, j)];
+                    q9[(3 * i + axis, 3 * j + axis)] = q[(i, j)];
+                }
+            }
+        }
+        (a9, q9)
+    }
+}
+
+#[derive(Debug)]
+pub struct AccelKalman {
+    x: Vector9, // Position, velocity, acceleration
+    p: Matrix9,
+    p_diag: [T; 6],
+    a: Matrix9,
+    q: Matrix9,
+    h: Matrix3x9,
+    h_acc: Matrix3x9,
+    r: Matrix3,
+    r_acc: Matrix3,
+    innovation: Vector3,
+    nis: T,
+    config: KalmanConfig,
+    dt: T, // Step of a and q
+    gate: Gate,
+}
+
+impl Default for AccelKalman {
+    fn default() -> Self {
+        AccelKalman::new(KalmanConfig::default())
+    }
+}
+
+impl AccelKalman {
+    pub fn new(config: KalmanConfig) -> AccelKalman {
+        let r_acc = Matrix3::identity() * (config.s_acc * config.s_acc);
+        let mut p = Matrix9::zeros();
+        p.fixed_view_mut::<6, 6>(0, 0).copy_from(&config.p());
+        p.fixed_view_mut::<3, 3>(ACC, ACC).copy_from(&r_acc);
+        let mut h = Matrix3x9::zeros();
+        h.fixed_view_mut::<3, 3>(0, 0).fill_diagonal(1.);
+        let mut h_acc = Matrix3x9::zeros();
+        h_acc.fixed_view_mut::<3, 3>(0, ACC).fill_diagonal(1.);
+
+        let mut filter = AccelKalman {
+            x: Vector9::zeros(),
+            p,
+            p_diag: [0.; 6],
+            a: Matrix9::identity(),
+            q: Matrix9::zeros(),
+            h,
+            h_acc,
+            r: config.r(),
+            r_acc,
+            innovation: Vector3::zeros(),
+            nis: 0.,
+            config,
+            dt: config.dt,
+            gate: Gate::new(config.gate, 3),
+        };
+        filter.set_time_step(config.dt);
+        filter.update_diag();
+        filter
+    }
+
+    fn set_time_step(&mut self, dt: T) {
+        self.dt = dt;
+        (self.a, self.q) = self.config.accel.discretize(dt);
+    }
+
+    fn update_diag(&mut self) {
+        self.p_diag
+            .copy_from_slice(&self.p.diagonal().as_slice()[..6]);
+    }
+
+    /// Estimated acceleration, m/s^2
+    pub fn get_acceleration(&self) -> &[T] {
+        &self.x.as_slice()[ACC..]
+    }
+}
+
+impl Estimator for AccelKalman {
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+        let rot = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let v0 = rot * vector![speed * KMH_TO_MS, 0., 0.];
+        self.x = Vector9::zeros();
+        self.x.fixed_rows_mut::<3>(0).copy_from(&pos);
+        self.x.fixed_rows_mut::<3>(3).copy_from(&v0);
+    }
+
+    /// `acc` measures the acceleration over the step, before the model
+    /// moves the state
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.set_time_step(dt);
+        }
+        let innovation = acc - self.h_acc * self.x;
+        update(
+            &mut self.x,
+            &mut self.p,
+            &self.h_acc,
+            innovation,
+            &self.r_acc,
+        )?;
+        self.x = self.a * self.x;
+        self.p = symmetrize(self.a * self.p * self.a.transpose() + self.q);
+        self.update_diag();
+        Ok(())
+    }
+
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        self.innovation = z - self.h * self.x;
+        let s = innovation_covariance(&self.p, &self.h, &self.r)?;
+        self.nis = nis(&s, &self.innovation);
+        if !self.gate.accept(self.nis) {
+            return Ok(());
+        }
+        self.x += joseph_update(&mut self.p, &self.h, &self.r, &s) * self.innovation;
+        self.update_diag();
+        Ok(())
+    }
+
+    /// Speed measurement, `speed` in km/h
+    fn correction_speed(&mut self, speed: T) -> Result<(), KalmanError> {
+        correct_speed(&mut self.x, &mut self.p, speed, self.config.s_speed)?;
+        self.update_diag();
+        Ok(())
+    }
+
+    /// Heading measurement from a DIRECTION (roll, pitch, yaw)
+    fn correction_direction(&mut self, dir: &Vector3) -> Result<(), KalmanError> {
+        correct_direction(&mut self.x, &mut self.p, dir, self.config.s_heading)?;
+        self.update_diag();
+        Ok(())
+    }
+
+    /// Position and velocity
+    fn get_state(&self) -> &[T] {
+        &self.x.as_slice()[..6]
+    }
+
+    fn get_state_variance(&self) -> &[T; 6] {
+        &self.p_diag
+    }
+
+    fn get_innovation(&self) -> &[T] {
+        self.innovation.as_slice()
+    }
+
+    fn get_nis(&self) -> T {
+        self.nis
+    }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use nalgebra::matrix;
+
+    /// The closed form of the constant acceleration model
+    #[test]
+    fn discretizes_the_white_jerk() {
+        let (dt, jerk) = (0.1, 2.);
+        let config = AccelConfig {
+            jerk,
+            ..AccelConfig::default()
+        };
+        let (a, q) = config.discretize(dt);
+        let a_x = matrix![
+            1., dt, 0.5 * dt * dt;
+            0., 1., dt;
+            0., 0., 1.
+        ];
+        let q_x = matrix![
+            dt.powi(5) / 20., dt.powi(4) / 8., dt.powi(3) / 6.;
+            dt.powi(4) / 8., dt.powi(3) / 3., dt * dt / 2.;
+            dt.powi(3) / 6., dt * dt / 2., dt
+        ] * (jerk * jerk);
+        let x_axis = [0, 3, 6];
+        for (i, row) in x_axis.into_iter().enumerate() {
+            for (j, col) in x_axis.into_iter().enumerate() {
+                assert!((a[(row, col)] - a_x[(i, j)]).abs() < 1e-12);
+                assert!((q[(row, col)] - q_x[(i, j)]).abs() < 1e-12);
+            }
+        }
+        assert_eq!(a[(0, 4)], 0.);
+    }
+
+    /// Singer's closed form, whose acceleration decays by
+    /// `exp(-dt / tau)` a step and settles to a variance of `sigma^2`
+    #[test]
+    fn discretizes_the_singer_model() {
+        let (dt, tau, sigma) = (0.5, 2., 1.5);
+        let config = AccelConfig {
+            model: AccelModel::Singer,
+            tau,
+            sigma,
+            ..AccelConfig::default()
+        };
+        let (a, q) = config.discretize(dt);
+        let alpha = 1. / tau;
+        let (at, e1, e2) = (alpha * dt, (-alpha * dt).exp(), (-2. * alpha * dt).exp());
+        let a_x = matrix![
+            1., dt, (at - 1. + e1) / (alpha * alpha);
+            0., 1., (1. - e1) / alpha;
+            0., 0., e1
+        ];
+        let q11 = (1. - e2 + 2. * at + 2. * at.powi(3) / 3. - 2. * at * at - 4. * at * e1)
+            / (2. * alpha.powi(5));
+        let q12 = (e2 + 1. - 2. * e1 + 2. * at * e1 - 2. * at + at * at) / (2. * alpha.powi(4));
+        let q13 = (1. - e2 - 2. * at * e1) / (2. * alpha.powi(3));
+        let q22 = (4. * e1 - 3. - e2 + 2. * at) / (2. * alpha.powi(3));
+        let q23 = (e2 + 1. - 2. * e1) / (2. * alpha * alpha);
+        let q33 = (1. - e2) / (2. * alpha);
+        let q_x = matrix![
+            q11, q12, q13;
+            q12, q22, q23;
+            q13, q23, q33
+        ] * (2. * alpha * sigma * sigma);
+        let y_axis = [1, 4, 7];
+        for (i, row) in y_axis.into_iter().enumerate() {
+            for (j, col) in y_axis.into_iter().enumerate() {
+                assert!((a[(row, col)] - a_x[(i, j)]).abs() < 1e-12);
+                assert!((q[(row, col)] - q_x[(i, j)]).abs() < 1e-12);
+            }
+        }
+
+        let decay = a[(ACC + 1, ACC + 1)];
+        assert!((decay - (-dt / tau).exp()).abs() < 1e-12);
+        let steady = q[(ACC + 1, ACC + 1)] / (1. - decay * decay);
+        assert!((steady - sigma * sigma).abs() < 1e-12);
+    }
+
+    /// A slow jerk averages the noisy ACCELERATIONs out
+    #[test]
+    fn filters_the_measured_acceleration() -> Result<(), KalmanError> {
+        let acc = vector![0.5, -0.2, 0.];
+        let config = KalmanConfig {
+            s_acc: 0.05,
+            accel: AccelConfig {
+                jerk: 0.01,
+                ..AccelConfig::default()
+            },
+            ..KalmanConfig::default()
+        };
+        let mut filter = AccelKalman::new(config);
+        let mut rng = Rng::new(1);
+        filter.init(Vector3::zeros(), 36., Vector3::zeros());
+        for step in 1..=3000 {
+            filter.prediction(&acc.map(|a| a + rng.normal(0.05)), None)?;
+            if step % 300 == 0 {
+                let t = step as T * config.dt;
+                let position = vector![10. * t, 0., 0.] + acc * (0.5 * t * t);
+                filter.correction(&position.map(|p| p + rng.normal(0.1)))?;
+            }
+        }
+        let error = (Vector3::from_column_slice(filter.get_acceleration()) - acc).norm();
+        assert!(error < 0.025, "{error} m/s^2");
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+//! Online estimation of the noise covariances from the GPS innovations.
+//!
+//! `R` follows covariance matching over a sliding window of fixes: the
+//! post-fit residual `e` of a consistent filter has `E[e e'] = R - H P H'`.
+//! `Q` keeps its shape and its scale follows the ratio of the observed to
+//! the predicted spread of the innovations once `R` is removed, a full
+//! window being needed to move by that ratio. Both are weakly observable
+//! together: the GPS residuals can absorb a `Q` taken too small, hence the
+//! bounds.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::types::{Matrix3, T, Vector3};
+
+/// Which covariances are estimated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Adapt {
+    #[default]
+    Off,
+    R,
+    Q,
+    Both,
+}
+
+impl FromStr for Adapt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Adapt::Off),
+            "r" => Ok(Adapt::R),
+            "q" => Ok(Adapt::Q),
+            "rq" => Ok(Adapt::Both),
+            _ => Err(format!("expected off, r, q or rq, got {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Adapt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Adapt::Off => "off",
+            Adapt::R => "r",
+            Adapt::Q => "q",
+            Adapt::Both => "rq",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveConfig {
+    pub mode: Adapt,
+    pub window: usize,  // GPS fixes
+    pub r_sigma_min: T, // m
+    pub r_sigma_max: T,
+    pub q_scale_min: T, // relative to the configured Q
+    pub q_scale_max: T,
+    /// Stop adapting after this many fixes, keeping the estimates
+    pub freeze_after: Option<usize>,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        AdaptiveConfig {
+            mode: Adapt::Off,
+            window: 20,
+            r_sigma_min: 1e-3,
+            r_sigma_max: 1e3,
+            q_scale_min: 1e-6,
+            q_scale_max: 1e2,
+            freeze_after: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AdaptiveNoise {
+    config: AdaptiveConfig,
+    frozen: bool,
+    fixes: usize,
+    residuals: VecDeque<Matrix3>, // e e' + H P H' after each fix
+    spreads: VecDeque<(T, T)>,    // |v|^2 - tr(R) and tr(H P H') before each fix
+    r: Matrix3,
+    q_scale: T,
+}
+
+impl AdaptiveNoise {
+    pub fn new(config: AdaptiveConfig, r: Matrix3) -> AdaptiveNoise {
+        AdaptiveNoise {
+            config,
+            r,
+            q_scale: 1.,
+            residuals: VecDeque::with_capacity(config.window),
+            ..Default::default()
+        }
+    }
+
+    pub fn r(&self) -> &Matrix3 {
+        &self.r
+    }
+
+    pub fn q_scale(&self) -> T {
+        self.q_scale
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    fn adapts_r(&self) -> bool {
+        matches!(self.config.mode, Adapt::R | Adapt::Both)
+    }
+
+    fn adapts_q(&self) -> bool {
+        matches!(self.config.mode, Adapt::Q | Adapt::Both)
+    }
+
+    /// Feed one fix: its innovation and `H P H'` before the update, the
+    /// residual and `H P H'` after it. True when `Q` must be rebuilt.
+    pub fn observe(
+        &mut self,
+        innovation: &Vector3,
+        hph_prior: &Matrix3,
+        residual: &Vector3,
+        hph_post: &Matrix3,
+    ) -> bool {
+        if self.config.mode == Adapt::Off || self.frozen {
+            return false;
+        }
+        self.fixes += 1;
+        if self
+            .config
+            .freeze_after
+            .is_some_and(|after| self.fixes >= after)
+        {
+            self.frozen = true;
+        }
+
+        if self.adapts_r() {
+            if self.residuals.len() == self.config.window {
+                self.residuals.pop_front();
+            }
+            self.residuals
+                .push_back(residual * residual.transpose() + hph_post);
+            let mean = self.residuals.iter().sum::<Matrix3>() / self.residuals.len() as T;
+            let (min, max) = (self.config.r_sigma_min, self.config.r_sigma_max);
+            // Only the diagonal, which stays positive definite once bounded
+            self.r =
+                Matrix3::from_diagonal(&mean.diagonal().map(|v| v.min(max * max).max(min * min)));
+        }
+
+        if !self.adapts_q() {
+            return false;
+        }
+        if self.spreads.len() == self.config.window {
+            self.spreads.pop_front();
+        }
+        self.spreads.push_back((
+            innovation.norm_squared() - self.r.trace(),
+            hph_prior.trace(),
+        ));
+        if self.spreads.len() < self.config.window {
+            return false;
+        }
+        let (observed, predicted) = self
+            .spreads
+            .iter()
+            .fold((0., 0.), |(o, p), (io, ip)| (o + io, p + ip));
+        let ratio = (observed / predicted)
+            .clamp(0.1, 10.)
+            .powf(1. / self.config.window as T);
+        let (min, max) = (self.config.q_scale_min, self.config.q_scale_max);
+        self.q_scale = (self.q_scale * ratio).min(max).max(min);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    /// Fixes whose residual has the variance `sigma^2 - hph` per axis
+    fn observe(noise: &mut AdaptiveNoise, rng: &mut Rng, sigma: T, fixes: usize) {
+        let hph = Matrix3::identity() * 0.01;
+        let residual_sigma = (sigma * sigma - 0.01).sqrt();
+        for _ in 0..fixes {
+            let residual = Vector3::from_fn(|_, _| rng.normal(residual_sigma));
+            noise.observe(&residual, &hph, &residual, &hph);
+        }
+    }
+
+    fn config(window: usize) -> AdaptiveConfig {
+        AdaptiveConfig {
+            mode: Adapt::R,
+            window,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn r_converges_to_the_residual_variance() {
+        let mut rng = Rng::new(1);
+        let mut noise = AdaptiveNoise::new(config(1000), Matrix3::identity() * 4.);
+        observe(&mut noise, &mut rng, 0.5, 1000);
+        for v in noise.r().diagonal().iter() {
+            assert!((v - 0.25).abs() < 0.025, "{v}");
+        }
+    }
+
+    /// Only the last window counts
+    #[test]
+    fn r_forgets_beyond_the_window() {
+        let mut rng = Rng::new(2);
+        let mut noise = AdaptiveNoise::new(config(200), Matrix3::identity());
+        observe(&mut noise, &mut rng, 10., 200);
+        observe(&mut noise, &mut rng, 0.5, 200);
+        for v in noise.r().diagonal().iter() {
+            assert!((v - 0.25).abs() < 0.05, "{v}");
+        }
+    }
+
+    #[test]
+    fn r_stays_within_its_bounds() {
+        let mut rng = Rng::new(3);
+        let bounded = AdaptiveConfig {
+            r_sigma_min: 0.2,
+            r_sigma_max: 0.3,
+            ..config(20)
+        };
+        let mut noise = AdaptiveNoise::new(bounded, Matrix3::identity());
+        observe(&mut noise, &mut rng, 1., 50);
+        assert_eq!(*noise.r(), Matrix3::identity() * (0.3 * 0.3));
+        observe(&mut noise, &mut rng, 0.11, 50);
+        assert_eq!(*noise.r(), Matrix3::identity() * (0.2 * 0.2));
+    }
+
+    #[test]
+    fn freezes_after_the_configured_fixes() {
+        let mut rng = Rng::new(4);
+        let frozen = AdaptiveConfig {
+            freeze_after: Some(100),
+            ..config(100)
+        };
+        let mut noise = AdaptiveNoise::new(frozen, Matrix3::identity());
+        observe(&mut noise, &mut rng, 0.5, 100);
+        assert!(noise.is_frozen());
+        let r = *noise.r();
+        observe(&mut noise, &mut rng, 10., 100);
+        assert_eq!(*noise.r(), r);
+    }
+
+    /// Innovations of `scale` times the predicted spread
+    fn observe_spread(noise: &mut AdaptiveNoise, scale: T, fixes: usize) -> Vec<bool> {
+        let hph = Matrix3::identity() * 0.01;
+        let r = noise.r().trace();
+        let innovation = Vector3::from_element(((r + scale * hph.trace()) / 3.).sqrt());
+        (0..fixes)
+            .map(|_| noise.observe(&innovation, &hph, &innovation, &hph))
+            .collect()
+    }
+
+    #[test]
+    fn q_scale_follows_the_innovations_within_its_bounds() {
+        let bounded = AdaptiveConfig {
+            mode: Adapt::Q,
+            window: 10,
+            q_scale_min: 0.5,
+            q_scale_max: 4.,
+            ..Default::default()
+        };
+        let mut noise = AdaptiveNoise::new(bounded, Matrix3::identity());
+
+        // Too large: up by at most 10 over a window, once it is full
+        let rebuilt = observe_spread(&mut noise, 20., 10);
+        assert_eq!(rebuilt, [vec![false; 9], vec![true]].concat());
+        assert!((noise.q_scale() - (10. as T).powf(0.1)).abs() < 1e-12);
+        let mut previous = noise.q_scale();
+        for _ in 0..30 {
+            observe_spread(&mut noise, 20., 1);
+            assert!(noise.q_scale() >= previous);
+            previous = noise.q_scale();
+        }
+        assert_eq!(noise.q_scale(), 4.);
+
+        // Too small, down to the lower bound
+        for _ in 0..60 {
+            observe_spread(&mut noise, -1., 1);
+            assert!(noise.q_scale() <= previous);
+            previous = noise.q_scale();
+        }
+        assert_eq!(noise.q_scale(), 0.5);
+        assert_eq!(*noise.r(), Matrix3::identity());
+    }
+}
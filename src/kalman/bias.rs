@@ -0,0 +1,218 @@
+//! Linear filter with the accelerometer bias as extra state.
+//!
+//! State is position, velocity and bias, the measured acceleration being the
+//! true one plus the bias. The bias follows a random walk, GPS fixes make it
+//! observable through the position drift it causes.
+
+use super::gate::Gate;
+use super::{
+    KMH_TO_MS, KalmanConfig, correct_direction, correct_speed, innovation_covariance,
+    joseph_update, nis,
+};
+use crate::{
+    error::KalmanError,
+    estimator::Estimator,
+    plot_data::PlotData,
+    types::{Matrix3, T, Vector3},
+};
+use nalgebra::{Rotation3, SMatrix, SVector, vector};
+
+const N: usize = 9;
+type Matrix9 = SMatrix<T, N, N>;
+type Matrix9x3 = SMatrix<T, N, 3>;
+type Matrix3x9 = SMatrix<T, 3, N>;
+type Vector9 = SVector<T, N>;
+
+const BIAS: usize = 6;
+
+#[derive(Debug)]
+pub struct BiasKalman {
+    x: Vector9, // Position, velocity, accelerometer bias
+    p: Matrix9,
+    p_diag: [T; 6],
+    bias_var: [T; 3],
+    a: Matrix9,
+    b: Matrix9x3,
+    h: Matrix3x9,
+    q: Matrix9,
+    r: Matrix3,
+    innovation: Vector3,
+    nis: T,
+    config: KalmanConfig,
+    dt: T, // Step of a, b and q
+    gate: Gate,
+}
+
+impl Default for BiasKalman {
+    fn default() -> Self {
+        BiasKalman::new(KalmanConfig::default())
+    }
+}
+
+impl BiasKalman {
+    pub fn new(config: KalmanConfig) -> BiasKalman {
+        let mut p = Matrix9::zeros();
+        p.fixed_view_mut::<6, 6>(0, 0).copy_from(&config.p());
+        p.fixed_view_mut::<3, 3>(BIAS, BIAS)
+            .fill_diagonal(config.s_bias_init * config.s_bias_init);
+
+        let mut h = Matrix3x9::zeros();
+        h.fixed_view_mut::<3, 3>(0, 0).fill_diagonal(1.);
+
+        let mut filter = BiasKalman {
+            x: Vector9::zeros(),
+            p,
+            p_diag: [0.; 6],
+            bias_var: [0.; 3],
+            a: Matrix9::identity(),
+            b: Matrix9x3::zeros(),
+            h,
+            q: Matrix9::zeros(),
+            r: config.r(),
+            innovation: Vector3::zeros(),
+            nis: 0.,
+            config,
+            dt: config.dt,
+            gate: Gate::new(config.gate, 3),
+        };
+        filter.set_time_step(config.dt);
+        filter.update_diag();
+        filter
+    }
+
+    fn set_time_step(&mut self, dt: T) {
+        self.dt = dt;
+        let b = self.config.b(dt);
+        self.b.fixed_rows_mut::<6>(0).copy_from(&b);
+
+        // The bias is subtracted from the measured acceleration
+        self.a
+            .fixed_view_mut::<6, 6>(0, 0)
+            .copy_from(&self.config.a(dt));
+        self.a.fixed_view_mut::<6, 3>(0, BIAS).copy_from(&-b);
+
+        self.q
+            .fixed_view_mut::<6, 6>(0, 0)
+            .copy_from(&self.config.q(dt));
+        self.q
+            .fixed_view_mut::<3, 3>(BIAS, BIAS)
+            .fill_diagonal(self.config.s_bias_walk * self.config.s_bias_walk * dt);
+    }
+
+    fn update_diag(&mut self) {
+        let diag = self.p.diagonal();
+        self.p_diag.copy_from_slice(&diag.as_slice()[..6]);
+        self.bias_var.copy_from_slice(&diag.as_slice()[BIAS..]);
+    }
+
+    /// Estimated accelerometer bias, m/s^2
+    pub fn get_bias(&self) -> &[T] {
+        &self.x.as_slice()[BIAS..]
+    }
+
+    pub fn get_bias_variance(&self) -> &[T; 3] {
+        &self.bias_var
+    }
+}
+
+impl Estimator for BiasKalman {
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+        let rot = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let v0 = rot * vector![speed * KMH_TO_MS, 0., 0.];
+        self.x = Vector9::zeros();
+        self.x.fixed_rows_mut::<3>(0).copy_from(&pos);
+        self.x.fixed_rows_mut::<3>(3).copy_from(&v0);
+    }
+
+    /// `acc` is the measured, biased, acceleration
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.set_time_step(dt);
+        }
+        self.x = self.a * self.x + self.b * acc;
+        self.p = self.a * self.p * self.a.transpose() + self.q;
+        self.update_diag();
+        Ok(())
+    }
+
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        self.innovation = z - self.h * self.x;
+        let s = innovation_covariance(&self.p, &self.h, &self.r)?;
+        self.nis = nis(&s, &self.innovation);
+        if !self.gate.accept(self.nis) {
+            return Ok(());
+        }
+        self.x += joseph_update(&mut self.p, &self.h, &self.r, &s) * self.innovation;
+        self.update_diag();
+        Ok(())
+    }
+
+    /// Speed measurement, `speed` in km/h
+    fn correction_speed(&mut self, speed: T) -> Result<(), KalmanError> {
+        correct_speed(&mut self.x, &mut self.p, speed, self.config.s_speed)?;
+        self.update_diag();
+        Ok(())
+    }
+
+    /// Heading measurement from a DIRECTION (roll, pitch, yaw)
+    fn correction_direction(&mut self, dir: &Vector3) -> Result<(), KalmanError> {
+        correct_direction(&mut self.x, &mut self.p, dir, self.config.s_heading)?;
+        self.update_diag();
+        Ok(())
+    }
+
+    /// Position and velocity
+    fn get_state(&self) -> &[T] {
+        &self.x.as_slice()[..6]
+    }
+
+    fn get_state_variance(&self) -> &[T; 6] {
+        &self.p_diag
+    }
+
+    fn get_innovation(&self) -> &[T] {
+        self.innovation.as_slice()
+    }
+
+    fn get_nis(&self) -> T {
+        self.nis
+    }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+
+    fn push_plot_data(&self, plot_data: &mut PlotData) {
+        plot_data.push_bias(self.get_bias(), self.get_bias_variance());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng::Rng, trajectory::Trajectory};
+
+    #[test]
+    fn estimates_the_injected_bias() -> Result<(), KalmanError> {
+        let bias = vector![0.015, -0.01, 0.02];
+        let mut filter = BiasKalman::default();
+        let mut rng = Rng::new(1);
+        let mut trajectory = Trajectory::new(1, 5);
+        let start = trajectory.next().unwrap();
+        filter.init(start.position, start.speed(), start.direction);
+        let mut error = 0.;
+        for point in trajectory {
+            let acc = (point.acceleration + bias).map(|a| a + rng.normal(1e-3));
+            filter.prediction(&acc, None)?;
+            if point.step % 300 == 0 {
+                filter.correction(&point.position.map(|p| p + rng.normal(0.1)))?;
+            }
+            error = (Vector3::from_column_slice(&filter.get_state()[..3]) - point.position).norm();
+        }
+        let estimated = Vector3::from_column_slice(filter.get_bias());
+        assert!((estimated - bias).norm() < 1e-3, "{estimated}");
+        assert!(error < 0.5, "{error} m");
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+//! Filter parameters, tunable at runtime from the command line or a
+//! `key = value` file.
+//!
+//! ```text
+//! # Noise sigmas are inflated well above the simulator's
+//! dt = 0.01
+//! s_acc = 0.125
+//! s_gps = 12.5
+//! s_speed = 0.1
+//! adapt = rq # off, r, q or rq
+//! gate_confidence = 0.999
+//! ukf_process = curvilinear # or linear
+//! accel_model = singer # or constant
+//! imm_markov = 0.998 0.001 0.001  0.001 0.998 0.001  0.001 0.001 0.998
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use super::accel::AccelConfig;
+use super::adaptive::AdaptiveConfig;
+use super::gate::GateConfig;
+use super::imm::{ImmConfig, parse_markov};
+use super::particle::ParticleConfig;
+use super::ukf::UkfConfig;
+use crate::{
+    error::KalmanError,
+    types::{Matrix3, Matrix6, Matrix6x3, T},
+};
+use nalgebra::{matrix, vector};
+
+const MULT: T = 50. * 2.5;
+const S_ACC: T = 1e-3 * MULT;
+const S_GYR: T = 1e-2 * MULT;
+const S_GPS: T = 1e-1 * MULT;
+const DT: T = 0.01;
+const S_SPEED: T = 1e-1;
+const S_HEADING: T = 1e-2 * MULT; // The DIRECTION noise, inflated like the others
+const S_BIAS_INIT: T = 1e-1;
+const S_BIAS_WALK: T = 1e-2;
+const S_ATTITUDE: T = 1e-1;
+const S_RATE: T = 5e-2;
+const S_RATE_INIT: T = 1e-1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KalmanConfig {
+    /// Step of a prediction whose elapsed time is unknown, s
+    pub dt: T,
+    pub s_acc: T, // ACCELERATION, m/s^2
+    pub s_gps: T, // POSITION, m
+    pub init_pos_sigma: T,
+    pub init_vel_sigma: T,
+    pub s_speed: T,   // SPEED, m/s
+    pub s_heading: T, // DIRECTION pitch and yaw, rad
+    /// Initial accelerometer bias of `BiasKalman`, m/s^2
+    pub s_bias_init: T,
+    /// Bias random walk of `BiasKalman`, m/s^2 per sqrt(s)
+    pub s_bias_walk: T,
+    /// DIRECTION of `Ins`, rad
+    pub s_attitude: T,
+    /// Angular rate random walk of `Ins`, rad/s per sqrt(s)
+    pub s_rate: T,
+    /// Initial angular rate of `Ins`, rad/s
+    pub s_rate_init: T,
+    /// Online estimation of `R` and `Q`, `Kalman` only
+    pub adaptive: AdaptiveConfig,
+    /// Rejection of the outlying GPS fixes
+    pub gate: GateConfig,
+    /// Process and sigma points of `Ukf`
+    pub ukf: UkfConfig,
+    /// Motion model of `AccelKalman`
+    pub accel: AccelConfig,
+    /// Model switching of `Imm`
+    pub imm: ImmConfig,
+    /// Cloud of `ParticleFilter`
+    pub particle: ParticleConfig,
+}
+
+impl Default for KalmanConfig {
+    fn default() -> Self {
+        KalmanConfig {
+            dt: DT,
+            s_acc: S_ACC,
+            s_gps: S_GPS,
+            init_pos_sigma: S_GPS,
+            init_vel_sigma: (S_GYR * S_GYR + S_ACC * S_ACC * DT).sqrt(),
+            s_speed: S_SPEED,
+            s_heading: S_HEADING,
+            s_bias_init: S_BIAS_INIT,
+            s_bias_walk: S_BIAS_WALK,
+            s_attitude: S_ATTITUDE,
+            s_rate: S_RATE,
+            s_rate_init: S_RATE_INIT,
+            adaptive: AdaptiveConfig::default(),
+            gate: GateConfig::default(),
+            ukf: UkfConfig::default(),
+            accel: AccelConfig::default(),
+            imm: ImmConfig::default(),
+            particle: ParticleConfig::default(),
+        }
+    }
+}
+
+impl KalmanConfig {
+    /// Defaults overridden by the `key = value` lines of `path`, `#` starts
+    /// a comment.
+    pub fn load(path: &Path) -> Result<KalmanConfig, KalmanError> {
+        let mut config = KalmanConfig::default();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                KalmanError::Config(format!(
+                    "{}:{}: expected key = value",
+                    path.display(),
+                    i + 1
+                ))
+            })?;
+            config.try_set(key.trim(), value.trim()).map_err(|err| {
+                KalmanError::Config(format!("{}:{}: {err}", path.display(), i + 1))
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Override one parameter, `key` being the field name, `adapt_`, `gate_`,
+    /// `ukf_`, `accel_`, `imm_` or `pf_` prefixed for `adaptive`, `gate`,
+    /// `ukf` (and its sigma points), `accel`, `imm` and `particle`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), KalmanError> {
+        self.try_set(key, value).map_err(KalmanError::Config)
+    }
+
+    fn try_set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let adaptive = &mut self.adaptive;
+        match key {
+            "adapt" => {
+                adaptive.mode = value.parse()?;
+                return Ok(());
+            }
+            "ukf_process" => {
+                self.ukf.process = value.parse()?;
+                return Ok(());
+            }
+            "ukf_beta" | "ukf_kappa" => {
+                let value: T = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                let points = &mut self.ukf.sigma_points;
+                if key == "ukf_beta" && (value.is_nan() || value < 0.) {
+                    return Err(format!("{key} must not be negative"));
+                }
+                // n + kappa scales the sigma point spread, n being 6
+                if key == "ukf_kappa" && (value.is_nan() || value <= -6.) {
+                    return Err(format!("{key} must be greater than -6"));
+                }
+                match key {
+                    "ukf_beta" => points.beta = value,
+                    _ => points.kappa = value,
+                }
+                return Ok(());
+            }
+            // The Cholesky factorisation has no tolerance, the key is kept
+            // for the files written for the pseudo-inverse
+            "pinv_tolerance" => {
+                let value: T = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                if !value.is_finite() || value <= 0. {
+                    return Err(format!("{key} must be positive"));
+                }
+                return Ok(());
+            }
+            "accel_model" => {
+                self.accel.model = value.parse()?;
+                return Ok(());
+            }
+            "imm_markov" => {
+                self.imm.markov = parse_markov(value)?;
+                return Ok(());
+            }
+            "gate_confidence" => {
+                let value: T = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                if !(value > 0. && value < 1.) {
+                    return Err(format!("{key} must be between 0 and 1"));
+                }
+                self.gate.confidence = Some(value);
+                return Ok(());
+            }
+            "pf_seed" => {
+                self.particle.seed = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                return Ok(());
+            }
+            "pf_ess" => {
+                let value: T = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                if !(value > 0. && value <= 1.) {
+                    return Err(format!("{key} must be between 0 and 1"));
+                }
+                self.particle.ess_threshold = value;
+                return Ok(());
+            }
+            "pf_dof" => {
+                let value: T = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                if value.is_nan() || value <= 0. {
+                    return Err(format!("{key} must be positive, inf for a Gaussian"));
+                }
+                self.particle.gps_dof = value;
+                return Ok(());
+            }
+            "adapt_window" | "adapt_freeze_after" | "gate_reset_after" | "pf_particles" => {
+                let value: usize = value.parse().map_err(|err| format!("{key}: {err}"))?;
+                if value == 0 {
+                    return Err(format!("{key} must be positive"));
+                }
+                match key {
+                    "adapt_window" => adaptive.window = value,
+                    "adapt_freeze_after" => adaptive.freeze_after = Some(value),
+                    "pf_particles" => self.particle.particles = value,
+                    _ => self.gate.reset_after = value,
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        let field = match key {
+            "dt" => &mut self.dt,
+            "s_acc" => &mut self.s_acc,
+            "s_gps" => &mut self.s_gps,
+            "init_pos_sigma" => &mut self.init_pos_sigma,
+            "init_vel_sigma" => &mut self.init_vel_sigma,
+            "s_speed" => &mut self.s_speed,
+            "s_heading" => &mut self.s_heading,
+            "s_bias_init" => &mut self.s_bias_init,
+            "s_bias_walk" => &mut self.s_bias_walk,
+            "s_attitude" => &mut self.s_attitude,
+            "s_rate" => &mut self.s_rate,
+            "s_rate_init" => &mut self.s_rate_init,
+            "adapt_r_min" => &mut adaptive.r_sigma_min,
+            "adapt_r_max" => &mut adaptive.r_sigma_max,
+            "adapt_q_min" => &mut adaptive.q_scale_min,
+            "adapt_q_max" => &mut adaptive.q_scale_max,
+            "ukf_alpha" => &mut self.ukf.sigma_points.alpha,
+            "accel_jerk" => &mut self.accel.jerk,
+            "accel_tau" => &mut self.accel.tau,
+            "accel_sigma" => &mut self.accel.sigma,
+            _ => return Err(format!("unknown key {key}")),
+        };
+        let value: T = value.parse().map_err(|err| format!("{key}: {err}"))?;
+        if !value.is_finite() || value <= 0. {
+            return Err(format!("{key} must be positive"));
+        }
+        *field = value;
+        Ok(())
+    }
+
+    /// State transition over `dt`
+    pub(super) fn a(&self, dt: T) -> Matrix6 {
+        matrix![
+            1.,0.,0.,dt,0.,0.;
+            0.,1.,0.,0.,dt,0.;
+            0.,0.,1.,0.,0.,dt;
+            0.,0.,0.,1.,0.,0.;
+            0.,0.,0.,0.,1.,0.;
+            0.,0.,0.,0.,0.,1.
+        ]
+    }
+
+    /// Control input over `dt`
+    pub(super) fn b(&self, dt: T) -> Matrix6x3 {
+        let dd = 0.5 * dt * dt;
+        matrix![
+            dd,0.,0.;
+            0.,dd,0.;
+            0.,0.,dd;
+            dt,0.,0.;
+            0.,dt,0.;
+            0.,0.,dt;
+        ]
+    }
+
+    /// Initial state covariance
+    pub(super) fn p(&self) -> Matrix6 {
+        let pos = self.init_pos_sigma * self.init_pos_sigma;
+        let vel = self.init_vel_sigma * self.init_vel_sigma;
+        Matrix6::from_diagonal(&vector![pos, pos, pos, vel, vel, vel])
+    }
+
+    /// Process noise of the acceleration over `dt`
+    pub(super) fn q(&self, dt: T) -> Matrix6 {
+        let b = self.b(dt);
+        b * b.transpose() * (self.s_acc * self.s_acc)
+    }
+
+    /// POSITION covariance
+    pub(super) fn r(&self) -> Matrix3 {
+        Matrix3::identity() * (self.s_gps * self.s_gps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kalman::accel::AccelModel;
+    use crate::kalman::ukf::{SigmaPoints, UkfProcess};
+
+    #[test]
+    fn sets_the_measurement_sigmas() -> Result<(), KalmanError> {
+        let mut config = KalmanConfig::default();
+        let keys = [
+            "s_speed",
+            "s_heading",
+            "s_bias_init",
+            "s_bias_walk",
+            "s_attitude",
+            "s_rate",
+            "s_rate_init",
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            config.set(key, &(i + 1).to_string())?;
+            assert!(config.set(key, "0").is_err());
+        }
+        let set = [
+            config.s_speed,
+            config.s_heading,
+            config.s_bias_init,
+            config.s_bias_walk,
+            config.s_attitude,
+            config.s_rate,
+            config.s_rate_init,
+        ];
+        assert_eq!(set, [1., 2., 3., 4., 5., 6., 7.]);
+        Ok(())
+    }
+
+    #[test]
+    fn sets_the_ukf() -> Result<(), KalmanError> {
+        let mut config = KalmanConfig::default();
+        config.set("ukf_process", "curvilinear")?;
+        config.set("ukf_alpha", "0.5")?;
+        config.set("ukf_beta", "0")?;
+        config.set("ukf_kappa", "-3")?;
+        assert_eq!(config.ukf.process, UkfProcess::Curvilinear);
+        assert_eq!(
+            config.ukf.sigma_points,
+            SigmaPoints {
+                alpha: 0.5,
+                beta: 0.,
+                kappa: -3.,
+            }
+        );
+        assert!(config.set("ukf_process", "turn").is_err());
+        assert!(config.set("ukf_kappa", "-6").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sets_the_accel_model() -> Result<(), KalmanError> {
+        let mut config = KalmanConfig::default();
+        config.set("accel_model", "singer")?;
+        config.set("accel_tau", "5")?;
+        config.set("accel_sigma", "0.5")?;
+        assert_eq!(config.accel.model, AccelModel::Singer);
+        assert_eq!((config.accel.tau, config.accel.sigma), (5., 0.5));
+        assert!(config.set("accel_model", "jerk").is_err());
+        assert!(config.set("accel_tau", "0").is_err());
+        Ok(())
+    }
+
+    /// `KalmanConfig::load` of `content`, written to a temporary file
+    fn load(name: &str, content: &str) -> Result<KalmanConfig, KalmanError> {
+        let path = std::env::temp_dir().join(format!("kalman-{name}-{}.conf", std::process::id()));
+        fs::write(&path, content)?;
+        let config = KalmanConfig::load(&path);
+        fs::remove_file(&path)?;
+        config
+    }
+
+    #[test]
+    fn loads_a_file_with_comments() -> Result<(), KalmanError> {
+        let config = load(
+            "comments",
+            "# Tuning\n\ndt = 0.02\n   \ns_gps=3 # m\nukf_process = curvilinear\n",
+        )?;
+        assert_eq!(config.dt, 0.02);
+        assert_eq!(config.s_gps, 3.);
+        assert_eq!(config.ukf.process, UkfProcess::Curvilinear);
+        assert_eq!(config.s_acc, KalmanConfig::default().s_acc);
+        Ok(())
+    }
+
+    #[test]
+    fn load_reports_the_line_of_an_error() {
+        for (content, error) in [
+            ("dt = 0.02\n# gps\ns_gsp = 3\n", "unknown key s_gsp"),
+            ("dt = 0.02\n\ns_gps 3\n", "expected key = value"),
+        ] {
+            match load("unknown", content) {
+                Err(KalmanError::Config(message)) => {
+                    assert!(message.contains(":3: "), "{message}");
+                    assert!(message.ends_with(error), "{message}");
+                }
+                other => panic!("{other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_the_deprecated_pinv_tolerance() -> Result<(), KalmanError> {
+        let mut config = KalmanConfig::default();
+        config.set("pinv_tolerance", "1e-5")?;
+        assert_eq!(config, KalmanConfig::default());
+        assert!(config.set("pinv_tolerance", "x").is_err());
+        Ok(())
+    }
+
+    /// The constants the filter had before it was configurable, the
+    /// velocity variance being rounded through its sigma
+    #[test]
+    fn defaults_are_the_baseline_tuning() {
+        let config = KalmanConfig::default();
+        let mult = 50. * 2.5;
+        let (s_acc, s_gyr, s_gps, dt) = (1e-3 * mult, 1e-2 * mult, 1e-1 * mult, 0.01);
+        assert_eq!(mult, 125.);
+        assert_eq!(config.dt, dt);
+        let pos = s_gps * s_gps;
+        let vel = s_gyr * s_gyr + s_acc * s_acc * dt;
+        let p = Matrix6::from_diagonal(&vector![pos, pos, pos, vel, vel, vel]);
+        assert!((config.p() - p).abs().max() < 1e-12 * vel);
+        assert_eq!(config.r(), Matrix3::identity() * pos);
+        let dd = 0.5 * dt * dt;
+        let b = matrix![
+            dd,0.,0.;
+            0.,dd,0.;
+            0.,0.,dd;
+            dt,0.,0.;
+            0.,dt,0.;
+            0.,0.,dt;
+        ];
+        assert_eq!(config.b(dt), b);
+        assert_eq!(config.q(dt), b * b.transpose() * (s_acc * s_acc));
+    }
+}
@@ -0,0 +1,189 @@
+//! Chi-square gating of the GPS fixes on their NIS.
+//!
+//! A consistent fix has a NIS following a chi-square law with as many
+//! degrees of freedom as the measurement, fixes beyond its quantile at the
+//! chosen confidence are taken for outliers (multipath jumps) and skipped.
+
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::types::T;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GateConfig {
+    /// Probability for a consistent fix to pass, `None` fuses every fix
+    pub confidence: Option<T>,
+    /// Consecutive rejections after which the next fix is fused anyway, the
+    /// filter having then more likely drifted than the GPS jumped
+    pub reset_after: usize,
+}
+
+impl Default for GateConfig {
+    fn default() -> Self {
+        GateConfig {
+            confidence: None,
+            reset_after: 10,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gate {
+    threshold: Option<T>,
+    reset_after: usize,
+    consecutive: usize,
+    fixes: usize,
+    rejected: usize,
+    forced: usize,
+}
+
+impl Gate {
+    /// `dof` is the dimension of the measurement
+    pub fn new(config: GateConfig, dof: usize) -> Gate {
+        Gate {
+            threshold: config.confidence.map(|p| chi_square_quantile(dof, p)),
+            reset_after: config.reset_after,
+            ..Default::default()
+        }
+    }
+
+    /// Whether a fix of this NIS is fused, counting it
+    pub fn accept(&mut self, nis: T) -> bool {
+        self.fixes += 1;
+        let Some(threshold) = self.threshold else {
+            return true;
+        };
+        if nis <= threshold {
+            self.consecutive = 0;
+            return true;
+        }
+        if self.consecutive >= self.reset_after {
+            self.consecutive = 0;
+            self.forced += 1;
+            return true;
+        }
+        self.consecutive += 1;
+        self.rejected += 1;
+        false
+    }
+
+    /// NIS above which fixes are rejected, `None` when gating is off
+    pub fn threshold(&self) -> Option<T> {
+        self.threshold
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// Fixes fused after `reset_after` consecutive rejections
+    pub fn forced(&self) -> usize {
+        self.forced
+    }
+}
+
+impl fmt::Display for Gate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.threshold {
+            Some(threshold) => write!(
+                f,
+                "GPS gate (NIS < {threshold:.2}): {} of {} fixes rejected, {} fused after {} rejections",
+                self.rejected, self.fixes, self.forced, self.reset_after
+            ),
+            None => write!(f, "GPS gate off: {} fixes fused", self.fixes),
+        }
+    }
+}
+
+/// Regularised lower incomplete gamma function of `dof / 2` at `x / 2`
+fn chi_square_cdf(dof: usize, x: T) -> T {
+    if x <= 0. {
+        return 0.;
+    }
+    let (s, y) = (dof as T / 2., x / 2.);
+    // Gamma of a half integer
+    let (mut gamma, mut k) = if dof.is_multiple_of(2) {
+        (1., 1.)
+    } else {
+        (PI.sqrt(), 0.5)
+    };
+    while k < s {
+        gamma *= k;
+        k += 1.;
+    }
+    let (mut term, mut sum) = (1. / s, 1. / s);
+    let mut n = 1.;
+    while term > sum * 1e-15 {
+        term *= y / (s + n);
+        sum += term;
+        n += 1.;
+    }
+    (sum * (s * y.ln() - y).exp() / gamma).min(1.)
+}
+
+/// NIS under which a consistent fix falls with probability `p`, by bisection
+fn chi_square_quantile(dof: usize, p: T) -> T {
+    let mut high = dof as T;
+    while chi_square_cdf(dof, high) < p {
+        high *= 2.;
+    }
+    let mut low = 0.;
+    for _ in 0..100 {
+        let mid = 0.5 * (low + high);
+        if chi_square_cdf(dof, mid) < p {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    high
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_match_the_tables() {
+        let table = [
+            (1, 0.95, 3.841),
+            (1, 0.99, 6.635),
+            (2, 0.95, 5.991),
+            (2, 0.99, 9.210),
+            (3, 0.95, 7.815),
+            (3, 0.99, 11.345),
+        ];
+        for (dof, p, quantile) in table {
+            let computed = chi_square_quantile(dof, p);
+            assert!(
+                (computed - quantile).abs() < 1e-3,
+                "{dof} dof at {p}: {computed}"
+            );
+        }
+    }
+
+    #[test]
+    fn fuses_after_reset_after_rejections() {
+        let config = GateConfig {
+            confidence: Some(0.99),
+            reset_after: 3,
+        };
+        let mut gate = Gate::new(config, 3);
+        assert!(gate.accept(1.));
+        for _ in 0..2 {
+            for _ in 0..3 {
+                assert!(!gate.accept(100.));
+            }
+            assert!(gate.accept(100.));
+        }
+        assert_eq!((gate.rejected(), gate.forced()), (6, 2));
+        // A fix within the gate starts the count again
+        assert!(!gate.accept(100.));
+        assert!(gate.accept(1.));
+        for _ in 0..3 {
+            assert!(!gate.accept(100.));
+        }
+        assert!(gate.accept(100.));
+        assert_eq!((gate.rejected(), gate.forced()), (10, 3));
+    }
+}
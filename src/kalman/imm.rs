@@ -0,0 +1,547 @@
+//! Interacting Multiple Model estimator.
+//!
+//! Several motion models run side by side on the position, velocity and
+//! acceleration state. Before each prediction their estimates are mixed
+//! according to a Markov chain of the model switches, and each GPS fix
+//! reweights the models by how well they predicted it.
+//!
+//! ACCELERATION measures the acceleration state of every model, and each
+//! then moves the position and velocity with its estimate, so that the
+//! covariances stay comparable when mixed. The constant acceleration model
+//! carries the acceleration over from the step before; the others take it
+//! from the measurement alone.
+
+use super::gate::Gate;
+use super::ukf::curvilinear;
+use super::{
+    KMH_TO_MS, KalmanConfig, MIN_SPEED, correct_direction, correct_speed, innovation_covariance,
+    joseph_update, nis, symmetrize, update,
+};
+use crate::{
+    error::KalmanError,
+    estimator::Estimator,
+    plot_data::PlotData,
+    types::{Matrix3, Matrix3x6, Matrix6, Matrix6x3, T, Vector3, Vector6},
+};
+use nalgebra::{Rotation3, SMatrix, SVector, vector};
+
+const MODELS: usize = 3;
+const TURN_Q: T = 1e1; // Relative to the configured Q
+const MANOEUVRE_JERK: T = 1.; // m/s^3 per sqrt(Hz)
+
+const N: usize = 9;
+type Matrix9 = SMatrix<T, N, N>;
+type Matrix3x9 = SMatrix<T, 3, N>;
+type Vector9 = SVector<T, N>;
+
+const ACC: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    /// Constant velocity between the measured accelerations, the model of
+    /// `Kalman`
+    Cruise,
+    /// Constant acceleration up to a white jerk, for braking
+    /// or accelerating harder than the accelerometer noise allows
+    Manoeuvre,
+    /// Coordinated turn at the rate the lateral acceleration implies
+    Turn,
+}
+
+impl Motion {
+    pub const ALL: [Motion; MODELS] = [Motion::Cruise, Motion::Manoeuvre, Motion::Turn];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Motion::Cruise => "Cruise",
+            Motion::Manoeuvre => "Manoeuvre",
+            Motion::Turn => "Turn",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImmConfig {
+    /// Probability to switch from the row model to the column one at each
+    /// prediction, models in `Motion::ALL` order
+    pub markov: Matrix3,
+}
+
+impl Default for ImmConfig {
+    fn default() -> Self {
+        ImmConfig {
+            markov: Matrix3::from_fn(|i, j| if i == j { 0.998 } else { 0.001 }),
+        }
+    }
+}
+
+/// Nine row-major probabilities, each row summing to 1
+pub(super) fn parse_markov(value: &str) -> Result<Matrix3, String> {
+    let values = value
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse::<T>().map_err(|err| format!("imm_markov: {err}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() != MODELS * MODELS {
+        return Err(format!(
+            "imm_markov expects {} probabilities, got {}",
+            MODELS * MODELS,
+            values.len()
+        ));
+    }
+    let mut markov = Matrix3::from_row_slice(&values);
+    for (i, mut row) in markov.row_iter_mut().enumerate() {
+        let sum = row.sum();
+        if row.iter().any(|p| p.is_nan() || *p < 0.) || (sum - 1.).abs() > 1e-6 {
+            return Err(format!(
+                "imm_markov row {} must be probabilities summing to 1",
+                i + 1
+            ));
+        }
+        row /= sum;
+    }
+    Ok(markov)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Model {
+    motion: Motion,
+    x: Vector9,
+    p: Matrix9,
+}
+
+#[derive(Debug)]
+pub struct Imm {
+    models: [Model; MODELS],
+    probabilities: [T; MODELS],
+    markov: Matrix3,
+    x: Vector9, // Combined estimate
+    p: Matrix9,
+    p_diag: [T; 6],
+    a: Matrix6, // Measured acceleration as input
+    b: Matrix6x3,
+    q: Matrix6,
+    kinematics: Matrix9, // Position and velocity moved by the acceleration
+    jerk: Matrix3,       // Acceleration change per step of the Manoeuvre model
+    h: Matrix3x9,
+    h_acc: Matrix3x9,
+    r: Matrix3,
+    r_acc: Matrix3,
+    innovation: Vector3,
+    nis: T,
+    config: KalmanConfig,
+    dt: T, // Step of a, b, q, kinematics and jerk
+    gate: Gate,
+}
+
+impl Default for Imm {
+    fn default() -> Self {
+        Imm::new(KalmanConfig::default())
+    }
+}
+
+impl Imm {
+    pub fn new(config: KalmanConfig) -> Imm {
+        let r_acc = Matrix3::identity() * (config.s_acc * config.s_acc);
+        let mut p = Matrix9::zeros();
+        p.fixed_view_mut::<6, 6>(0, 0).copy_from(&config.p());
+        p.fixed_view_mut::<3, 3>(ACC, ACC).copy_from(&r_acc);
+        let mut h = Matrix3x9::zeros();
+        h.fixed_view_mut::<3, 3>(0, 0).fill_diagonal(1.);
+        let mut h_acc = Matrix3x9::zeros();
+        h_acc.fixed_view_mut::<3, 3>(0, ACC).fill_diagonal(1.);
+
+        let models = Motion::ALL.map(|motion| Model {
+            motion,
+            x: Vector9::zeros(),
+            p,
+        });
+        let mut filter = Imm {
+            models,
+            probabilities: [1. / MODELS as T; MODELS],
+            markov: config.imm.markov,
+            x: Vector9::zeros(),
+            p,
+            p_diag: [0.; 6],
+            a: Matrix6::zeros(),
+            b: Matrix6x3::zeros(),
+            q: Matrix6::zeros(),
+            kinematics: Matrix9::identity(),
+            jerk: Matrix3::zeros(),
+            h,
+            h_acc,
+            r: config.r(),
+            r_acc,
+            innovation: Vector3::zeros(),
+            nis: 0.,
+            config,
+            dt: config.dt,
+            gate: Gate::new(config.gate, 3),
+        };
+        filter.set_time_step(config.dt);
+        filter.combine();
+        filter
+    }
+
+    fn set_time_step(&mut self, dt: T) {
+        self.dt = dt;
+        self.a = self.config.a(dt);
+        self.b = self.config.b(dt);
+        self.q = self.config.q(dt);
+        self.kinematics = kinematics(dt);
+        self.jerk = Matrix3::identity() * (MANOEUVRE_JERK * MANOEUVRE_JERK * dt);
+    }
+
+    /// Probability of each of `Motion::ALL`
+    pub fn probabilities(&self) -> &[T; MODELS] {
+        &self.probabilities
+    }
+
+    /// Each model restarts from the position and velocity of all, weighted
+    /// by the chance that it switched from them. Its acceleration follows
+    /// through its own covariance with them, as mixing it too would
+    /// correlate it with the spread of the velocities.
+    fn mix(&mut self) {
+        let predicted = self.markov.tr_mul(&Vector3::from(self.probabilities));
+        let models = self.models;
+        for (j, model) in self.models.iter_mut().enumerate() {
+            if predicted[j] <= 0. {
+                continue;
+            }
+            let weights: [T; MODELS] =
+                std::array::from_fn(|i| self.markov[(i, j)] * self.probabilities[i] / predicted[j]);
+            let x: Vector6 = models
+                .iter()
+                .zip(weights)
+                .map(|(from, w)| from.x.fixed_rows::<6>(0) * w)
+                .sum();
+            let p: Matrix6 = models
+                .iter()
+                .zip(weights)
+                .map(|(from, w)| {
+                    let dx = from.x.fixed_rows::<6>(0) - x;
+                    (from.p.fixed_view::<6, 6>(0, 0) + dx * dx.transpose()) * w
+                })
+                .sum();
+            // Regression of the acceleration on the position and velocity
+            let cross = model.p.fixed_view::<6, 3>(0, ACC).into_owned();
+            let gain: Matrix3x6 = model
+                .p
+                .fixed_view::<6, 6>(0, 0)
+                .into_owned()
+                .cholesky()
+                .map_or(Matrix3x6::zeros(), |p| p.solve(&cross).transpose());
+            let acc = model.x.fixed_rows::<3>(ACC) + gain * (x - model.x.fixed_rows::<6>(0));
+            let p_acc =
+                model.p.fixed_view::<3, 3>(ACC, ACC) - gain * cross + gain * p * gain.transpose();
+            model.x.fixed_rows_mut::<6>(0).copy_from(&x);
+            model.x.fixed_rows_mut::<3>(ACC).copy_from(&acc);
+            model.p.fixed_view_mut::<6, 6>(0, 0).copy_from(&p);
+            model
+                .p
+                .fixed_view_mut::<3, 6>(ACC, 0)
+                .copy_from(&(gain * p));
+            model
+                .p
+                .fixed_view_mut::<6, 3>(0, ACC)
+                .copy_from(&(p * gain.transpose()));
+            model
+                .p
+                .fixed_view_mut::<3, 3>(ACC, ACC)
+                .copy_from(&symmetrize(p_acc));
+        }
+        self.probabilities = predicted.into();
+    }
+
+    /// Moment-matched mixture of the models
+    fn combine(&mut self) {
+        self.x = self
+            .models
+            .iter()
+            .zip(self.probabilities)
+            .map(|(model, mu)| model.x * mu)
+            .sum();
+        self.p = symmetrize(
+            self.models
+                .iter()
+                .zip(self.probabilities)
+                .map(|(model, mu)| {
+                    let dx = model.x - self.x;
+                    (model.p + dx * dx.transpose()) * mu
+                })
+                .sum(),
+        );
+        self.p_diag
+            .copy_from_slice(&self.p.diagonal().as_slice()[..6]);
+    }
+
+    /// Estimated acceleration, m/s^2
+    pub fn get_acceleration(&self) -> &[T] {
+        &self.x.as_slice()[ACC..]
+    }
+
+    /// The measured acceleration becomes the acceleration state,
+    /// independent of the position and velocity, before moving them
+    fn predict_driven(&self, model: &mut Model, acc: &Vector3, dt: T) {
+        model.x.fixed_rows_mut::<3>(ACC).copy_from(acc);
+        model.p.fixed_view_mut::<6, 3>(0, ACC).fill(0.);
+        model.p.fixed_view_mut::<3, 6>(ACC, 0).fill(0.);
+        model
+            .p
+            .fixed_view_mut::<3, 3>(ACC, ACC)
+            .copy_from(&self.r_acc);
+        if model.motion == Motion::Turn {
+            let x = model.x.fixed_rows::<6>(0).into_owned();
+            let mut f = self.kinematics;
+            f.fixed_view_mut::<6, 6>(0, 0)
+                .copy_from(&turn_transition(&x, acc, &self.a, dt));
+            model
+                .x
+                .fixed_rows_mut::<6>(0)
+                .copy_from(&curvilinear(&x, acc, dt));
+            model.p = f * model.p * f.transpose();
+            let mut p = model.p.fixed_view_mut::<6, 6>(0, 0);
+            p += self.q * (TURN_Q - 1.);
+        } else {
+            model.x = self.kinematics * model.x;
+            model.p = self.kinematics * model.p * self.kinematics.transpose();
+        }
+    }
+
+    /// The acceleration state drifts by the jerk and `acc` measures it,
+    /// before moving the position and velocity
+    fn predict_accelerating(&self, model: &mut Model, acc: &Vector3) -> Result<(), KalmanError> {
+        let mut p = model.p.fixed_view_mut::<3, 3>(ACC, ACC);
+        p += self.jerk;
+        let innovation = acc - self.h_acc * model.x;
+        update(
+            &mut model.x,
+            &mut model.p,
+            &self.h_acc,
+            innovation,
+            &self.r_acc,
+        )?;
+        model.x = self.kinematics * model.x;
+        model.p = self.kinematics * model.p * self.kinematics.transpose();
+        Ok(())
+    }
+}
+
+/// Position and velocity moved over `dt` by the acceleration state
+fn kinematics(dt: T) -> Matrix9 {
+    let mut a = Matrix9::identity();
+    for axis in 0..3 {
+        a[(axis, 3 + axis)] = dt;
+        a[(axis, ACC + axis)] = 0.5 * dt * dt;
+        a[(3 + axis, ACC + axis)] = dt;
+    }
+    a
+}
+
+/// Jacobian of `curvilinear`, the turn rate held fixed over the step
+fn turn_transition(x: &Vector6, acc: &Vector3, a: &Matrix6, dt: T) -> Matrix6 {
+    let speed2 = x[3] * x[3] + x[4] * x[4];
+    if speed2 < MIN_SPEED * MIN_SPEED {
+        return *a;
+    }
+    let yaw_rate = (x[3] * acc.y - x[4] * acc.x) / speed2;
+    let angle = yaw_rate * dt;
+    if angle.abs() < 1e-9 {
+        return *a;
+    }
+    let (sin, cos) = angle.sin_cos();
+    let mut turn = *a;
+    turn[(0, 3)] = sin / yaw_rate;
+    turn[(0, 4)] = -(1. - cos) / yaw_rate;
+    turn[(1, 3)] = (1. - cos) / yaw_rate;
+    turn[(1, 4)] = sin / yaw_rate;
+    turn[(3, 3)] = cos;
+    turn[(3, 4)] = -sin;
+    turn[(4, 3)] = sin;
+    turn[(4, 4)] = cos;
+    turn
+}
+
+impl Estimator for Imm {
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+        let rot = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let v0 = rot * vector![speed * KMH_TO_MS, 0., 0.];
+        for model in &mut self.models {
+            model.x = Vector9::zeros();
+            model.x.fixed_rows_mut::<3>(0).copy_from(&pos);
+            model.x.fixed_rows_mut::<3>(3).copy_from(&v0);
+        }
+        self.combine();
+    }
+
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.set_time_step(dt);
+        }
+        self.mix();
+        let mut models = self.models;
+        for model in &mut models {
+            match model.motion {
+                Motion::Manoeuvre => self.predict_accelerating(model, acc)?,
+                Motion::Cruise | Motion::Turn => self.predict_driven(model, acc, dt),
+            }
+        }
+        self.models = models;
+        self.combine();
+        Ok(())
+    }
+
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        self.innovation = z - self.h * self.x;
+        let s = innovation_covariance(&self.p, &self.h, &self.r)?;
+        self.nis = nis(&s, &self.innovation);
+        if !self.gate.accept(self.nis) {
+            return Ok(());
+        }
+        // Gaussian log-likelihood of the fix under each model, up to a
+        // constant
+        let mut likelihood = [0.; MODELS];
+        for (model, likelihood) in self.models.iter_mut().zip(&mut likelihood) {
+            let innovation = z - self.h * model.x;
+            let s = innovation_covariance(&model.p, &self.h, &self.r)?;
+            let log_det = 2. * s.l_dirty().diagonal().map(T::ln).sum();
+            *likelihood = -0.5 * (nis(&s, &innovation) + log_det);
+            model.x += joseph_update(&mut model.p, &self.h, &self.r, &s) * innovation;
+        }
+        let max = likelihood.iter().copied().fold(T::NEG_INFINITY, T::max);
+        let weights: [T; MODELS] =
+            std::array::from_fn(|j| self.probabilities[j] * (likelihood[j] - max).exp());
+        let total: T = weights.iter().sum();
+        if total > 0. {
+            self.probabilities = weights.map(|w| w / total);
+        }
+        self.combine();
+        Ok(())
+    }
+
+    /// Speed measurement, `speed` in km/h
+    fn correction_speed(&mut self, speed: T) -> Result<(), KalmanError> {
+        for model in &mut self.models {
+            correct_speed(&mut model.x, &mut model.p, speed, self.config.s_speed)?;
+        }
+        self.combine();
+        Ok(())
+    }
+
+    /// Heading measurement from a DIRECTION (roll, pitch, yaw)
+    fn correction_direction(&mut self, dir: &Vector3) -> Result<(), KalmanError> {
+        for model in &mut self.models {
+            correct_direction(&mut model.x, &mut model.p, dir, self.config.s_heading)?;
+        }
+        self.combine();
+        Ok(())
+    }
+
+    /// Position and velocity
+    fn get_state(&self) -> &[T] {
+        &self.x.as_slice()[..6]
+    }
+
+    fn get_state_variance(&self) -> &[T; 6] {
+        &self.p_diag
+    }
+
+    fn get_innovation(&self) -> &[T] {
+        self.innovation.as_slice()
+    }
+
+    fn get_nis(&self) -> T {
+        self.nis
+    }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+
+    fn push_plot_data(&self, plot_data: &mut PlotData) {
+        plot_data.push_models(
+            &Motion::ALL.map(|motion| motion.name()),
+            &self.probabilities,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng::Rng, trajectory::Trajectory};
+
+    #[test]
+    fn parses_a_markov_matrix() -> Result<(), String> {
+        let markov = parse_markov("0.9 0.05 0.05, 0 1 0; 0.2 0.3 0.5")?;
+        assert_eq!(
+            markov.row(1).iter().copied().collect::<Vec<_>>(),
+            [0., 1., 0.]
+        );
+        assert_eq!(markov[(2, 2)], 0.5);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_markov_matrices() {
+        for (value, error) in [
+            ("1 0 0  0 1 0  0 0", "expects 9 probabilities, got 8"),
+            ("1 0 0  0 1 0  0 0 1 0", "expects 9 probabilities, got 10"),
+            ("1 0 0  0 1 0  0 0 one", "imm_markov: "),
+            ("1 0 0  -0.5 1.5 0  0 0 1", "row 2 must be"),
+            ("1 0 0  0 1 0  0.5 0.2 0.2", "row 3 must be"),
+        ] {
+            let err = parse_markov(value).unwrap_err();
+            assert!(err.contains(error), "{value}: {err}");
+        }
+    }
+
+    #[test]
+    fn probabilities_stay_normalised() -> Result<(), KalmanError> {
+        let mut imm = Imm::default();
+        let mut rng = Rng::new(1);
+        let mut trajectory = Trajectory::new(1, 1);
+        let start = trajectory.next().unwrap();
+        imm.init(start.position, start.speed(), start.direction);
+        for point in trajectory {
+            imm.prediction(&point.acceleration.map(|a| a + rng.normal(1e-3)), None)?;
+            if point.step % 300 == 0 {
+                imm.correction(&point.position.map(|p| p + rng.normal(0.1)))?;
+            }
+            let probabilities = imm.probabilities();
+            assert!(probabilities.iter().all(|p| (0. ..=1.).contains(p)));
+            assert!((probabilities.iter().sum::<T>() - 1.).abs() < 1e-12);
+        }
+        Ok(())
+    }
+
+    /// A 20 Hz circle at 20 m/s and 0.5 rad/s with a fix every half
+    /// second, which a straight prediction overshoots
+    #[test]
+    fn a_constant_turn_favours_the_turn_model() -> Result<(), KalmanError> {
+        let (dt, speed, yaw_rate) = (0.05, 20., 0.5);
+        let radius = speed / yaw_rate;
+        let mut imm = Imm::new(KalmanConfig {
+            s_gps: 0.1,
+            ..KalmanConfig::default()
+        });
+        imm.init(
+            Vector3::new(0., -radius, 0.),
+            speed / KMH_TO_MS,
+            Vector3::zeros(),
+        );
+        for step in 1..=1200 {
+            let (sin, cos) = (yaw_rate * step as T * dt).sin_cos();
+            let acc = Vector3::new(-sin, cos, 0.) * (speed * yaw_rate);
+            imm.prediction(&acc, Some(dt))?;
+            if step % 10 == 0 {
+                imm.correction(&(Vector3::new(sin, -cos, 0.) * radius))?;
+            }
+        }
+        let turn = Motion::ALL.iter().position(|m| *m == Motion::Turn).unwrap();
+        let probabilities = imm.probabilities();
+        assert!(probabilities[turn] > 0.8, "{probabilities:?}");
+        Ok(())
+    }
+}
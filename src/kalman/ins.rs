@@ -0,0 +1,271 @@
+//! Inertial navigation filter for accelerometers fixed to the vehicle.
+//!
+//! The nominal state is position, velocity, attitude and a constant angular
+//! rate in the navigation frame. The filter runs on the error state
+//! `(dp, dv, dtheta, dw)`, `dtheta` being a small rotation applied on the
+//! left of the attitude, which keeps the covariance away from Euler angle
+//! singularities.
+
+use super::gate::Gate;
+use super::{KMH_TO_MS, KalmanConfig, MIN_SPEED, innovation_covariance, joseph_update, nis};
+use crate::{
+    error::KalmanError,
+    estimator::Estimator,
+    types::{Matrix3, Matrix6, Matrix6x3, T, Vector3, Vector6},
+};
+use nalgebra::{Cholesky, Const, Matrix1, Rotation3, SMatrix, SVector, vector};
+
+const N: usize = 12;
+type MatrixN = SMatrix<T, N, N>;
+type VectorN = SVector<T, N>;
+
+const POS: usize = 0;
+const VEL: usize = 3;
+const ATT: usize = 6;
+const RATE: usize = 9;
+
+#[derive(Debug)]
+pub struct Ins {
+    x: Vector6, // Position and velocity
+    attitude: Rotation3<T>,
+    rate: Vector3, // Navigation frame angular rate
+    p: MatrixN,    // Error state covariance
+    p_diag: [T; 6],
+    q: MatrixN,
+    a: Matrix6,
+    b: Matrix6x3,
+    r: Matrix3,
+    config: KalmanConfig,
+    dt: T, // Step of a, b and q
+    gate: Gate,
+    innovation: Vector3,
+    nis: T,
+}
+
+impl Default for Ins {
+    fn default() -> Self {
+        Ins::new(KalmanConfig::default())
+    }
+}
+
+impl Ins {
+    pub fn new(config: KalmanConfig) -> Ins {
+        let mut p = MatrixN::zeros();
+        p.fixed_view_mut::<6, 6>(POS, POS).copy_from(&config.p());
+        p.fixed_view_mut::<3, 3>(ATT, ATT)
+            .fill_diagonal(config.s_attitude * config.s_attitude);
+        p.fixed_view_mut::<3, 3>(RATE, RATE)
+            .fill_diagonal(config.s_rate_init * config.s_rate_init);
+        let mut filter = Ins {
+            x: Vector6::zeros(),
+            attitude: Rotation3::identity(),
+            rate: Vector3::zeros(),
+            p_diag: [0.; 6],
+            p,
+            q: MatrixN::zeros(),
+            a: Matrix6::zeros(),
+            b: Matrix6x3::zeros(),
+            r: config.r(),
+            config,
+            dt: config.dt,
+            gate: Gate::new(config.gate, 3),
+            innovation: Vector3::zeros(),
+            nis: 0.,
+        };
+        filter.set_time_step(config.dt);
+        filter
+    }
+
+    fn set_time_step(&mut self, dt: T) {
+        self.dt = dt;
+        self.a = self.config.a(dt);
+        self.b = self.config.b(dt);
+        self.q
+            .fixed_view_mut::<6, 6>(POS, POS)
+            .copy_from(&self.config.q(dt));
+        self.q
+            .fixed_view_mut::<3, 3>(RATE, RATE)
+            .fill_diagonal(self.config.s_rate * self.config.s_rate * dt);
+    }
+
+    fn update<const M: usize>(
+        &mut self,
+        h: &SMatrix<T, M, N>,
+        r: &SMatrix<T, M, M>,
+        innovation: SVector<T, M>,
+    ) -> Result<(), KalmanError> {
+        let s = innovation_covariance(&self.p, h, r)?;
+        self.apply(h, r, &s, innovation);
+        Ok(())
+    }
+
+    /// `s` being the factor of the innovation covariance
+    fn apply<const M: usize>(
+        &mut self,
+        h: &SMatrix<T, M, N>,
+        r: &SMatrix<T, M, M>,
+        s: &Cholesky<T, Const<M>>,
+        innovation: SVector<T, M>,
+    ) {
+        let k = joseph_update(&mut self.p, h, r, s);
+        self.inject(&(k * innovation));
+        self.update_p_diag();
+    }
+
+    /// Fold an error state estimate into the nominal state
+    fn inject(&mut self, dx: &VectorN) {
+        self.x += dx.fixed_rows::<6>(POS);
+        self.attitude = Rotation3::new(dx.fixed_rows::<3>(ATT).into_owned()) * self.attitude;
+        self.rate += dx.fixed_rows::<3>(RATE);
+    }
+
+    fn update_p_diag(&mut self) {
+        for (i, var) in self.p_diag.iter_mut().enumerate() {
+            *var = self.p[(i, i)];
+        }
+    }
+
+    fn position(&self) -> Vector3 {
+        self.x.fixed_rows::<3>(POS).into_owned()
+    }
+
+    fn velocity(&self) -> Vector3 {
+        self.x.fixed_rows::<3>(VEL).into_owned()
+    }
+
+    /// Euler angles (roll, pitch, yaw)
+    pub fn get_attitude(&self) -> Vector3 {
+        let (roll, pitch, yaw) = self.attitude.euler_angles();
+        vector![roll, pitch, yaw]
+    }
+
+    /// Navigation frame angular rate, rad/s
+    pub fn get_rate(&self) -> &Vector3 {
+        &self.rate
+    }
+}
+
+impl Estimator for Ins {
+    /// `speed` in km/h, `dir` as Euler angles (roll, pitch, yaw)
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+        self.attitude = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let v0 = self.attitude * vector![speed * KMH_TO_MS, 0., 0.];
+        self.x = vector![pos.x, pos.y, pos.z, v0.x, v0.y, v0.z];
+        self.rate = Vector3::zeros();
+    }
+
+    /// `acc` is measured in the vehicle frame
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.set_time_step(dt);
+        }
+        let f = self.attitude * acc;
+        self.x = self.a * self.x + self.b * f;
+        self.attitude = Rotation3::new(self.rate * dt) * self.attitude;
+
+        // An attitude error tilts the acceleration: d(f) = -[f]x dtheta
+        let skew = -f.cross_matrix();
+        let dd = 0.5 * dt * dt;
+        let mut a = MatrixN::identity();
+        a.fixed_view_mut::<3, 3>(POS, VEL)
+            .copy_from(&(Matrix3::identity() * dt));
+        a.fixed_view_mut::<3, 3>(POS, ATT).copy_from(&(skew * dd));
+        a.fixed_view_mut::<3, 3>(VEL, ATT).copy_from(&(skew * dt));
+        a.fixed_view_mut::<3, 3>(ATT, RATE)
+            .copy_from(&(Matrix3::identity() * dt));
+        self.p = a * self.p * a.transpose() + self.q;
+        self.update_p_diag();
+        Ok(())
+    }
+
+    /// GPS position
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        let mut h = SMatrix::<T, 3, N>::zeros();
+        h.fixed_view_mut::<3, 3>(0, POS).fill_diagonal(1.);
+        self.innovation = z - self.position();
+        let r = self.r;
+        let s = innovation_covariance(&self.p, &h, &r)?;
+        self.nis = nis(&s, &self.innovation);
+        if self.gate.accept(self.nis) {
+            self.apply(&h, &r, &s, self.innovation);
+        }
+        Ok(())
+    }
+
+    /// Speed measurement, `speed` in km/h: z = |v|.
+    fn correction_speed(&mut self, speed: T) -> Result<(), KalmanError> {
+        let v = self.velocity();
+        let norm = v.norm();
+        if norm < MIN_SPEED {
+            return Ok(());
+        }
+        let mut h = SMatrix::<T, 1, N>::zeros();
+        h.fixed_view_mut::<1, 3>(0, VEL).tr_copy_from(&(v / norm));
+        let r = Matrix1::new(self.config.s_speed * self.config.s_speed);
+        self.update(&h, &r, vector![speed * KMH_TO_MS - norm])
+    }
+
+    /// Attitude measurement from a DIRECTION (roll, pitch, yaw)
+    fn correction_direction(&mut self, dir: &Vector3) -> Result<(), KalmanError> {
+        let measured = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let innovation = (measured * self.attitude.inverse()).scaled_axis();
+        let mut h = SMatrix::<T, 3, N>::zeros();
+        h.fixed_view_mut::<3, 3>(0, ATT).fill_diagonal(1.);
+        let r = Matrix3::identity() * (self.config.s_attitude * self.config.s_attitude);
+        self.update(&h, &r, innovation)
+    }
+
+    /// Position and velocity
+    fn get_state(&self) -> &[T] {
+        self.x.as_slice()
+    }
+
+    fn get_state_variance(&self) -> &[T; 6] {
+        &self.p_diag
+    }
+
+    fn get_innovation(&self) -> &[T] {
+        self.innovation.as_slice()
+    }
+
+    fn get_nis(&self) -> T {
+        self.nis
+    }
+
+    fn needs_direction(&self) -> bool {
+        true
+    }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng::Rng, trajectory::Trajectory};
+
+    /// Body frame accelerations and noisy DIRECTIONs, as `imu_sim --body`
+    /// sends them
+    #[test]
+    fn follows_the_trajectory_from_body_accelerations() -> Result<(), KalmanError> {
+        let mut ins = Ins::default();
+        let mut rng = Rng::new(1);
+        let mut trajectory = Trajectory::new(1, 2);
+        let start = trajectory.next().unwrap();
+        ins.init(start.position, start.speed(), start.direction);
+        for point in trajectory {
+            let acc = point.body_acceleration().map(|a| a + rng.normal(1e-3));
+            ins.prediction(&acc, None)?;
+            ins.correction_direction(&point.direction.map(|d| d + rng.normal(1e-2)))?;
+            if point.step % 300 == 0 {
+                ins.correction(&point.position.map(|p| p + rng.normal(0.1)))?;
+            }
+            let error = (ins.position() - point.position).norm();
+            assert!(error < 1., "step {}: {error} m", point.step);
+        }
+        Ok(())
+    }
+}
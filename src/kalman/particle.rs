@@ -0,0 +1,386 @@
+//! Bootstrap particle filter over the position and velocity state.
+//!
+//! Particles follow the `Kalman` model with the acceleration noise sampled,
+//! GPS fixes reweight them by a Student-t likelihood whose heavy tails keep
+//! an outlying fix from wiping out the cloud. The particles are resampled,
+//! systematically, once their effective number falls below a fraction of
+//! the total, and jittered (regularised) to keep the cloud diverse.
+//!
+//! The sigmas should be close to the true noise: inflated ones draw a cloud
+//! much wider than the errors, whose sampling error then dominates. The
+//! filter earns its cost on non-Gaussian GPS errors.
+
+use super::gate::Gate;
+use super::{H, KMH_TO_MS, KalmanConfig, innovation_covariance, nis, symmetrize};
+use crate::{
+    error::KalmanError,
+    estimator::Estimator,
+    rng::Rng,
+    types::{Matrix3, Matrix6, Matrix6x3, T, Vector3, Vector6},
+};
+use nalgebra::{Rotation3, vector};
+
+const N: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleConfig {
+    pub particles: usize,
+    /// Resample when the effective sample size falls below this fraction of
+    /// `particles`
+    pub ess_threshold: T,
+    pub seed: u64,
+    /// Degrees of freedom of the GPS error, infinite for a Gaussian
+    pub gps_dof: T,
+}
+
+impl Default for ParticleConfig {
+    fn default() -> Self {
+        ParticleConfig {
+            particles: 1000,
+            ess_threshold: 0.5,
+            seed: 0,
+            gps_dof: 4.,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParticleFilter {
+    particles: Vec<Vector6>,
+    weights: Vec<T>, // Normalised
+    x: Vector6,      // Weighted mean
+    p: Matrix6,      // Weighted covariance
+    p_diag: [T; 6],
+    a: Matrix6,
+    b: Matrix6x3,
+    r: Matrix3,
+    innovation: Vector3,
+    nis: T,
+    config: KalmanConfig,
+    dt: T, // Step of a and b
+    rng: Rng,
+    gate: Gate,
+    resamples: usize,
+}
+
+impl Default for ParticleFilter {
+    fn default() -> Self {
+        ParticleFilter::new(KalmanConfig::default())
+    }
+}
+
+impl ParticleFilter {
+    pub fn new(config: KalmanConfig) -> ParticleFilter {
+        let n = config.particle.particles;
+        let mut filter = ParticleFilter {
+            particles: vec![Vector6::zeros(); n],
+            weights: vec![1. / n as T; n],
+            x: Vector6::zeros(),
+            p: config.p(),
+            p_diag: [0.; 6],
+            a: Matrix6::zeros(),
+            b: Matrix6x3::zeros(),
+            r: config.r(),
+            innovation: Vector3::zeros(),
+            nis: 0.,
+            config,
+            dt: config.dt,
+            rng: Rng::new(config.particle.seed),
+            gate: Gate::new(config.gate, 3),
+            resamples: 0,
+        };
+        filter.set_time_step(config.dt);
+        filter
+            .p_diag
+            .copy_from_slice(filter.p.diagonal().as_slice());
+        filter
+    }
+
+    fn set_time_step(&mut self, dt: T) {
+        self.dt = dt;
+        self.a = self.config.a(dt);
+        self.b = self.config.b(dt);
+    }
+
+    /// Effective sample size, `1 / sum(w^2)`
+    pub fn ess(&self) -> T {
+        1. / self.weights.iter().map(|w| w * w).sum::<T>()
+    }
+
+    pub fn resamples(&self) -> usize {
+        self.resamples
+    }
+
+    fn sample(&mut self, sigma: T) -> Vector3 {
+        vector![
+            self.rng.normal(sigma),
+            self.rng.normal(sigma),
+            self.rng.normal(sigma)
+        ]
+    }
+
+    /// GPS log-likelihood of a particle, up to a constant
+    fn log_likelihood(&self, error: &Vector3) -> T {
+        let (dof, sigma2) = (self.config.particle.gps_dof, self.r[(0, 0)]);
+        let d2 = error.norm_squared() / sigma2;
+        if dof.is_infinite() {
+            -0.5 * d2
+        } else {
+            -0.5 * (dof + 3.) * (d2 / dof).ln_1p()
+        }
+    }
+
+    /// Keeps each particle about `n w` times, a single uniform draw setting
+    /// all the picks, then spreads the copies apart
+    fn resample(&mut self) {
+        let n = self.particles.len();
+        let step = 1. / n as T;
+        let mut pick = self.rng.uniform() * step;
+        let (mut i, mut cumulative) = (0, self.weights[0]);
+        let mut resampled = Vec::with_capacity(n);
+        for _ in 0..n {
+            while pick > cumulative && i + 1 < n {
+                i += 1;
+                cumulative += self.weights[i];
+            }
+            resampled.push(self.particles[i]);
+            pick += step;
+        }
+        self.particles = resampled;
+        self.weights.fill(step);
+        self.resamples += 1;
+        self.regularise();
+    }
+
+    /// Gaussian kernel jitter of the optimal bandwidth, shaped by the cloud
+    /// covariance. The acceleration noise alone moves the copies of a
+    /// particle too little apart for the cloud to recover its spread.
+    fn regularise(&mut self) {
+        let Some(cholesky) = self.p.cholesky() else {
+            return;
+        };
+        let n = self.particles.len() as T;
+        let bandwidth = (4. / (n * (N as T + 2.))).powf(1. / (N as T + 4.));
+        let l = cholesky.l() * bandwidth;
+        for i in 0..self.particles.len() {
+            let noise = Vector6::from_fn(|_, _| self.rng.gaussian());
+            self.particles[i] += l * noise;
+        }
+    }
+
+    fn update_moments(&mut self) {
+        self.x = self
+            .particles
+            .iter()
+            .zip(&self.weights)
+            .map(|(x, w)| x * *w)
+            .sum();
+        self.p = symmetrize(
+            self.particles
+                .iter()
+                .zip(&self.weights)
+                .map(|(x, w)| {
+                    let dx = x - self.x;
+                    dx * dx.transpose() * *w
+                })
+                .sum(),
+        );
+        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+    }
+}
+
+impl Estimator for ParticleFilter {
+    /// Draws the cloud around the initial state with the configured
+    /// initial sigmas
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+        let rot = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let v0 = rot * vector![speed * KMH_TO_MS, 0., 0.];
+        let (s_pos, s_vel) = (self.config.init_pos_sigma, self.config.init_vel_sigma);
+        for i in 0..self.particles.len() {
+            let p = pos + self.sample(s_pos);
+            let v = v0 + self.sample(s_vel);
+            self.particles[i] = vector![p.x, p.y, p.z, v.x, v.y, v.z];
+        }
+        self.weights.fill(1. / self.particles.len() as T);
+        self.update_moments();
+    }
+
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.set_time_step(dt);
+        }
+        for i in 0..self.particles.len() {
+            let acc = acc + self.sample(self.config.s_acc);
+            self.particles[i] = self.a * self.particles[i] + self.b * acc;
+        }
+        self.update_moments();
+        Ok(())
+    }
+
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        self.innovation = z - H * self.x;
+        let s = innovation_covariance(&self.p, &H, &self.r)?;
+        self.nis = nis(&s, &self.innovation);
+        if !self.gate.accept(self.nis) {
+            return Ok(());
+        }
+        let log_likelihood: Vec<T> = self
+            .particles
+            .iter()
+            .map(|x| self.log_likelihood(&(z - H * x)))
+            .collect();
+        let max = log_likelihood.iter().copied().fold(T::NEG_INFINITY, T::max);
+        for (w, l) in self.weights.iter_mut().zip(&log_likelihood) {
+            *w *= (l - max).exp();
+        }
+        let total: T = self.weights.iter().sum();
+        self.weights.iter_mut().for_each(|w| *w /= total);
+        // The moments of the weighted cloud, free of the resampling noise
+        self.update_moments();
+        if self.ess() < self.config.particle.ess_threshold * self.particles.len() as T {
+            self.resample();
+        }
+        Ok(())
+    }
+
+    fn get_state(&self) -> &[T] {
+        self.x.as_slice()
+    }
+
+    fn get_state_variance(&self) -> &[T; 6] {
+        &self.p_diag
+    }
+
+    fn get_innovation(&self) -> &[T] {
+        self.innovation.as_slice()
+    }
+
+    fn get_nis(&self) -> T {
+        self.nis
+    }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(seed: u64) -> KalmanConfig {
+        let mut config = KalmanConfig::default();
+        config.particle.particles = 200;
+        config.particle.seed = seed;
+        config
+    }
+
+    /// A straight line at constant speed, fixed every `gps_period` steps,
+    /// `fixed` getting the true position and the noisy fix
+    fn run(
+        filter: &mut ParticleFilter,
+        steps: usize,
+        gps_period: usize,
+        mut fixed: impl FnMut(&mut ParticleFilter, &Vector3, &Vector3) -> Result<(), KalmanError>,
+    ) -> Result<(), KalmanError> {
+        let mut noise = Rng::new(1);
+        filter.init(Vector3::zeros(), 36., Vector3::zeros());
+        for step in 1..=steps {
+            filter.prediction(&Vector3::zeros(), None)?;
+            if step % gps_period == 0 {
+                let t = step as T * filter.config.dt;
+                let truth = vector![10. * t, 0., 0.];
+                let z = truth.map(|p| p + noise.normal(0.1));
+                fixed(filter, &truth, &z)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn same_seed_is_bit_identical() -> Result<(), KalmanError> {
+        let mut states = Vec::new();
+        for seed in [3, 3, 4] {
+            let mut filter = ParticleFilter::new(config(seed));
+            run(&mut filter, 300, 30, |filter, _, z| filter.correction(z))?;
+            states.push((filter.x, filter.p, filter.resamples()));
+        }
+        assert_eq!(states[0], states[1]);
+        assert_ne!(states[0].0, states[2].0);
+        Ok(())
+    }
+
+    /// Resampling fires exactly when the reweighted cloud falls below the
+    /// threshold, and leaves the weights uniform
+    #[test]
+    fn resamples_below_the_ess_threshold() -> Result<(), KalmanError> {
+        let mut filter = ParticleFilter::new(config(0));
+        let n = filter.particles.len() as T;
+        let threshold = filter.config.particle.ess_threshold * n;
+        let (mut resampled, mut kept) = (0, 0);
+        run(&mut filter, 900, 30, |filter, _, z| {
+            let weights: Vec<T> = filter
+                .particles
+                .iter()
+                .zip(&filter.weights)
+                .map(|(x, w)| w * filter.log_likelihood(&(z - H * x)).exp())
+                .collect();
+            let total: T = weights.iter().sum();
+            let ess = total * total / weights.iter().map(|w| w * w).sum::<T>();
+            let resamples = filter.resamples();
+            filter.correction(z)?;
+            if filter.resamples() > resamples {
+                assert!(ess < threshold, "resampled at an ESS of {ess}");
+                assert!(filter.weights.iter().all(|w| *w == 1. / n));
+                resampled += 1;
+            } else {
+                assert!(ess >= threshold, "kept an ESS of {ess}");
+                assert!((filter.ess() - ess).abs() < 1e-9 * n);
+                kept += 1;
+            }
+            Ok(())
+        })?;
+        assert!(
+            resampled > 0 && kept > 0,
+            "{resampled} resampled, {kept} kept"
+        );
+        Ok(())
+    }
+
+    /// With sigmas matching the simulated noise
+    #[test]
+    fn tracks_the_line() -> Result<(), KalmanError> {
+        let mut config = config(0);
+        config.s_acc = 2e-3;
+        config.s_gps = 0.1;
+        config.init_pos_sigma = 0.01;
+        config.init_vel_sigma = 0.05;
+        let mut filter = ParticleFilter::new(config);
+        run(&mut filter, 3000, 30, |filter, truth, z| {
+            filter.correction(z)?;
+            let error = (H * filter.x - truth).norm();
+            assert!(error < 0.3, "{error} m off the line");
+            Ok(())
+        })
+    }
+
+    /// How far a single fix 50 m off the estimate moves the mean
+    fn jump(gps_dof: T) -> Result<T, KalmanError> {
+        let mut config = config(0);
+        config.particle.gps_dof = gps_dof;
+        let mut filter = ParticleFilter::new(config);
+        run(&mut filter, 900, 30, |filter, _, z| filter.correction(z))?;
+        let before = H * filter.x;
+        filter.correction(&(before + vector![0., 50., 0.]))?;
+        Ok((H * filter.x - before).norm())
+    }
+
+    #[test]
+    fn heavy_tails_resist_an_outlier() -> Result<(), KalmanError> {
+        let student = jump(ParticleConfig::default().gps_dof)?;
+        let gaussian = jump(T::INFINITY)?;
+        assert!(student < 0.3 * gaussian, "{student} m against {gaussian} m");
+        Ok(())
+    }
+}
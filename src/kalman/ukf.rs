@@ -0,0 +1,502 @@
+//! Unscented Kalman filter over the position and velocity state.
+//!
+//! The process and measurement functions may be any nonlinear function, the
+//! state distribution is carried through them by sigma points instead of
+//! Jacobians.
+
+use super::gate::Gate;
+use super::{KMH_TO_MS, KalmanConfig, MIN_SPEED, symmetrize, wrap_angle};
+use crate::{
+    error::KalmanError,
+    estimator::Estimator,
+    types::{Matrix3, Matrix6, T, Vector3, Vector6},
+};
+use nalgebra::{Matrix1, Matrix2, Rotation3, SMatrix, SVector, vector};
+use std::fmt;
+use std::str::FromStr;
+
+const N: usize = 6;
+const SIGMAS: usize = 2 * N + 1;
+
+/// `x' = f(x, acc, dt)`
+pub type Process = Box<dyn Fn(&Vector6, &Vector3, T) -> Vector6 + Send>;
+/// `z = h(x)`
+pub type Measurement = Box<dyn Fn(&Vector6) -> Vector3 + Send>;
+
+/// Sigma point spread (`alpha`), prior knowledge of the distribution
+/// (`beta`, 2 is optimal for a Gaussian) and secondary scaling (`kappa`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SigmaPoints {
+    pub alpha: T,
+    pub beta: T,
+    pub kappa: T,
+}
+
+impl Default for SigmaPoints {
+    fn default() -> Self {
+        SigmaPoints {
+            alpha: 1e-3,
+            beta: 2.,
+            kappa: 0.,
+        }
+    }
+}
+
+/// Process function `Ukf::new` picks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UkfProcess {
+    /// `linear`, the model of `Kalman`
+    #[default]
+    Linear,
+    /// `curvilinear`, turning the velocity by the lateral acceleration
+    Curvilinear,
+}
+
+impl FromStr for UkfProcess {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(UkfProcess::Linear),
+            "curvilinear" => Ok(UkfProcess::Curvilinear),
+            _ => Err(format!("expected linear or curvilinear, got {s}")),
+        }
+    }
+}
+
+impl fmt::Display for UkfProcess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            UkfProcess::Linear => "linear",
+            UkfProcess::Curvilinear => "curvilinear",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UkfConfig {
+    pub process: UkfProcess,
+    pub sigma_points: SigmaPoints,
+}
+
+/// Sigma point parameters with the derived scaling and weights
+#[derive(Debug, Clone, Copy)]
+struct Weights {
+    points: SigmaPoints,
+    scale: T,
+    mean: [T; SIGMAS],
+    cov: [T; SIGMAS],
+}
+
+impl Weights {
+    fn new(points: SigmaPoints) -> Weights {
+        let scale = points.alpha * points.alpha * (N as T + points.kappa);
+        let lambda = scale - N as T;
+        let mut mean = [0.5 / scale; SIGMAS];
+        let mut cov = mean;
+        mean[0] = lambda / scale;
+        cov[0] = mean[0] + 1. - points.alpha * points.alpha + points.beta;
+        Weights {
+            points,
+            scale,
+            mean,
+            cov,
+        }
+    }
+}
+
+/// Constant acceleration over the step, the model of `Kalman`
+pub fn linear(x: &Vector6, acc: &Vector3, dt: T) -> Vector6 {
+    let (p, v) = (x.fixed_rows::<3>(0), x.fixed_rows::<3>(3));
+    let p = p + v * dt + acc * (0.5 * dt * dt);
+    let v = v + acc * dt;
+    vector![p.x, p.y, p.z, v.x, v.y, v.z]
+}
+
+/// The lateral acceleration turns the horizontal velocity at a constant
+/// rate instead of being added to it, which keeps the speed through sharp
+/// turns. The vertical axis stays linear.
+pub fn curvilinear(x: &Vector6, acc: &Vector3, dt: T) -> Vector6 {
+    let v = x.fixed_rows::<3>(3);
+    let speed2 = v.x * v.x + v.y * v.y;
+    if speed2 < MIN_SPEED * MIN_SPEED {
+        return linear(x, acc, dt);
+    }
+    let speed = speed2.sqrt();
+    let yaw_rate = (v.x * acc.y - v.y * acc.x) / speed2;
+    let along = (v.x * acc.x + v.y * acc.y) / speed;
+    let turn = Rotation3::from_euler_angles(0., 0., yaw_rate * dt);
+    let horizontal = turn * vector![v.x, v.y, 0.] * ((speed + along * dt) / speed);
+    let v_next = vector![horizontal.x, horizontal.y, v.z + acc.z * dt];
+    let p = x.fixed_rows::<3>(0) + (v + v_next) * (0.5 * dt);
+    vector![p.x, p.y, p.z, v_next.x, v_next.y, v_next.z]
+}
+
+/// GPS position
+pub fn position(x: &Vector6) -> Vector3 {
+    x.fixed_rows::<3>(0).into_owned()
+}
+
+pub struct Ukf {
+    x: Vector6,
+    p: Matrix6,
+    p_diag: [T; 6],
+    q: Matrix6,
+    r: Matrix3,
+    config: KalmanConfig,
+    dt: T, // Step of q
+    weights: Weights,
+    process: Process,
+    measurement: Measurement,
+    gate: Gate,
+    innovation: Vector3,
+    nis: T,
+}
+
+impl Default for Ukf {
+    fn default() -> Self {
+        Ukf::new(KalmanConfig::default())
+    }
+}
+
+impl Ukf {
+    /// Process and sigma points of `config.ukf`, noise of `Kalman`, see
+    /// `with_process` and `with_measurement` for other functions.
+    pub fn new(config: KalmanConfig) -> Ukf {
+        let process: Process = match config.ukf.process {
+            UkfProcess::Linear => Box::new(linear),
+            UkfProcess::Curvilinear => Box::new(curvilinear),
+        };
+        Ukf {
+            x: Vector6::zeros(),
+            p: config.p(),
+            p_diag: [0.; 6],
+            q: config.q(config.dt),
+            r: config.r(),
+            config,
+            dt: config.dt,
+            weights: Weights::new(config.ukf.sigma_points),
+            process,
+            measurement: Box::new(position),
+            gate: Gate::new(config.gate, 3),
+            innovation: Vector3::zeros(),
+            nis: 0.,
+        }
+    }
+
+    pub fn with_sigma_points(mut self, sigma_points: SigmaPoints) -> Ukf {
+        self.weights = Weights::new(sigma_points);
+        self
+    }
+
+    pub fn with_process(
+        mut self,
+        process: impl Fn(&Vector6, &Vector3, T) -> Vector6 + Send + 'static,
+    ) -> Ukf {
+        self.process = Box::new(process);
+        self
+    }
+
+    /// Function of the POSITION messages
+    pub fn with_measurement(
+        mut self,
+        measurement: impl Fn(&Vector6) -> Vector3 + Send + 'static,
+    ) -> Ukf {
+        self.measurement = Box::new(measurement);
+        self
+    }
+
+    pub fn sigma_points(&self) -> &SigmaPoints {
+        &self.weights.points
+    }
+}
+
+impl Estimator for Ukf {
+    fn init(&mut self, pos: Vector3, speed: T, dir: Vector3) {
+        let rot = Rotation3::from_euler_angles(dir[0], dir[1], dir[2]);
+        let v0 = rot * vector![speed * KMH_TO_MS, 0., 0.];
+        self.x = vector![pos.x, pos.y, pos.z, v0.x, v0.y, v0.z];
+    }
+
+    fn prediction(&mut self, acc: &Vector3, dt: Option<T>) -> Result<(), KalmanError> {
+        let dt = dt.unwrap_or(self.config.dt);
+        if dt != self.dt {
+            self.dt = dt;
+            self.q = self.config.q(dt);
+        }
+        let sigmas = spread(&self.x, &self.p, &self.weights)?.map(|x| (self.process)(&x, acc, dt));
+        self.x = weighted_mean(&self.weights.mean, &sigmas);
+        self.p = self.q;
+        for (w, x) in self.weights.cov.iter().zip(&sigmas) {
+            let d = x - self.x;
+            self.p += *w * d * d.transpose();
+        }
+        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        Ok(())
+    }
+
+    fn correction(&mut self, z: &Vector3) -> Result<(), KalmanError> {
+        let measurement = &self.measurement;
+        let (innovation, nis) = unscented_update(
+            &mut self.x,
+            &mut self.p,
+            &self.weights,
+            |x| measurement(x) - z,
+            &self.r,
+            Some(&mut self.gate),
+        )?;
+        self.innovation = -innovation;
+        self.nis = nis;
+        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        Ok(())
+    }
+
+    /// Speed measurement, `speed` in km/h: z = |v|.
+    fn correction_speed(&mut self, speed: T) -> Result<(), KalmanError> {
+        if self.x.fixed_rows::<3>(3).norm() < MIN_SPEED {
+            return Ok(());
+        }
+        let speed = speed * KMH_TO_MS;
+        unscented_update(
+            &mut self.x,
+            &mut self.p,
+            &self.weights,
+            |x| vector![x.fixed_rows::<3>(3).norm() - speed],
+            &Matrix1::new(self.config.s_speed * self.config.s_speed),
+            None,
+        )?;
+        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        Ok(())
+    }
+
+    /// Heading measurement from a DIRECTION (roll, pitch, yaw): z = (pitch,
+    /// yaw) of the velocity.
+    fn correction_direction(&mut self, dir: &Vector3) -> Result<(), KalmanError> {
+        let v = self.x.fixed_rows::<3>(3);
+        if v.x.hypot(v.y) < MIN_SPEED {
+            return Ok(());
+        }
+        let (pitch, yaw) = (dir[1], dir[2]);
+        unscented_update(
+            &mut self.x,
+            &mut self.p,
+            &self.weights,
+            |x| {
+                let v = x.fixed_rows::<3>(3);
+                vector![
+                    wrap_angle((-v.z).atan2(v.x.hypot(v.y)) - pitch),
+                    wrap_angle(v.y.atan2(v.x) - yaw)
+                ]
+            },
+            &Matrix2::from_diagonal_element(self.config.s_heading * self.config.s_heading),
+            None,
+        )?;
+        self.p_diag.copy_from_slice(self.p.diagonal().as_slice());
+        Ok(())
+    }
+
+    fn get_state(&self) -> &[T] {
+        self.x.as_slice()
+    }
+
+    fn get_state_variance(&self) -> &[T; 6] {
+        &self.p_diag
+    }
+
+    fn get_innovation(&self) -> &[T] {
+        self.innovation.as_slice()
+    }
+
+    fn get_nis(&self) -> T {
+        self.nis
+    }
+
+    fn gate(&self) -> Option<&Gate> {
+        Some(&self.gate)
+    }
+}
+
+/// Mean and the symmetric points around it
+fn spread(x: &Vector6, p: &Matrix6, weights: &Weights) -> Result<[Vector6; SIGMAS], KalmanError> {
+    let sqrt = (p * weights.scale)
+        .cholesky()
+        .ok_or(KalmanError::NotPositiveDefinite("State covariance"))?
+        .l();
+    let mut sigmas = [*x; SIGMAS];
+    for i in 0..N {
+        sigmas[1 + i] += sqrt.column(i);
+        sigmas[1 + N + i] -= sqrt.column(i);
+    }
+    Ok(sigmas)
+}
+
+fn weighted_mean<const M: usize>(
+    w: &[T; SIGMAS],
+    points: &[SVector<T, M>; SIGMAS],
+) -> SVector<T, M> {
+    w.iter()
+        .zip(points)
+        .fold(SVector::zeros(), |mean, (w, x)| mean + *w * x)
+}
+
+/// Update for a measurement given as its residual `h(x) - z`, so that angles
+/// can be wrapped, skipped if `gate` rejects it. Returns the mean residual
+/// and its NIS.
+fn unscented_update<const M: usize>(
+    x: &mut Vector6,
+    p: &mut Matrix6,
+    weights: &Weights,
+    residual: impl Fn(&Vector6) -> SVector<T, M>,
+    r: &SMatrix<T, M, M>,
+    gate: Option<&mut Gate>,
+) -> Result<(SVector<T, M>, T), KalmanError> {
+    let sigmas = spread(x, p, weights)?;
+    let residuals = sigmas.map(|x| residual(&x));
+    let mean = weighted_mean(&weights.mean, &residuals);
+    let mut s = *r;
+    let mut cross = SMatrix::<T, N, M>::zeros();
+    for ((w, sigma), z) in weights.cov.iter().zip(&sigmas).zip(&residuals) {
+        let dz = z - mean;
+        s += *w * dz * dz.transpose();
+        cross += *w * (sigma - *x) * dz.transpose();
+    }
+    let s_chol = symmetrize(s)
+        .cholesky()
+        .ok_or(KalmanError::NotPositiveDefinite("Innovation covariance"))?;
+    let nis = mean.dot(&s_chol.solve(&mean));
+    if gate.is_some_and(|gate| !gate.accept(nis)) {
+        return Ok((mean, nis));
+    }
+    // K = C S^-1, solved as S K' = C'
+    let k = s_chol.solve(&cross.transpose()).transpose();
+    *x -= k * mean;
+    *p = symmetrize(*p - k * s * k.transpose());
+    Ok((mean, nis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kalman::Kalman, trajectory::Trajectory};
+
+    /// The sigma points carry a Gaussian exactly through the linear process
+    /// and measurement
+    #[test]
+    fn matches_kalman_on_the_linear_process() -> Result<(), KalmanError> {
+        let config = KalmanConfig::default();
+        let mut kalman = Kalman::new(config);
+        let mut ukf = Ukf::new(config);
+        let (pos, dir) = (vector![1., 2., 3.], vector![0., 0.1, 0.5]);
+        kalman.init(pos, 50., dir);
+        ukf.init(pos, 50., dir);
+        for step in 1..=1000 {
+            let t = step as T * config.dt;
+            let acc = vector![t.sin(), 0.5, -0.1];
+            kalman.prediction(&acc, None)?;
+            ukf.prediction(&acc, None)?;
+            if step % 100 == 0 {
+                let z = pos + vector![14., 2., 0.5] * t;
+                kalman.correction(&z)?;
+                ukf.correction(&z)?;
+            }
+        }
+        for (k, u) in kalman.get_state().iter().zip(ukf.get_state()) {
+            assert!((k - u).abs() < 1e-6 * k.abs().max(1.), "state {k} != {u}");
+        }
+        let variances = kalman
+            .get_state_variance()
+            .iter()
+            .zip(ukf.get_state_variance());
+        for (k, u) in variances {
+            assert!((k - u).abs() < 1e-6 * k, "variance {k} != {u}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_sigma_points_are_an_error() {
+        for sigma_points in [
+            SigmaPoints {
+                alpha: 0.,
+                ..Default::default()
+            },
+            SigmaPoints {
+                kappa: -(N as T),
+                ..Default::default()
+            },
+            SigmaPoints {
+                kappa: -10.,
+                ..Default::default()
+            },
+        ] {
+            let mut ukf = Ukf::default().with_sigma_points(sigma_points);
+            ukf.init(Vector3::zeros(), 50., Vector3::zeros());
+            assert!(
+                matches!(
+                    ukf.prediction(&Vector3::zeros(), None),
+                    Err(KalmanError::NotPositiveDefinite(_))
+                ),
+                "{sigma_points:?}"
+            );
+        }
+    }
+
+    /// An acceleration square to the velocity turns it at `a / v` without
+    /// changing the speed
+    #[test]
+    fn curvilinear_turns_at_the_lateral_rate() {
+        let (speed, yaw_rate, dt) = (20., 0.5, 0.1);
+        let mut x = vector![0., 0., 0., speed, 0., 0.];
+        for step in 1..=100 {
+            let v = x.fixed_rows::<3>(3);
+            let acc = vector![-v.y, v.x, 0.] * yaw_rate;
+            x = curvilinear(&x, &acc, dt);
+            let v = x.fixed_rows::<3>(3);
+            assert!((v.norm() - speed).abs() < 1e-9, "{}", v.norm());
+            let heading = wrap_angle(yaw_rate * step as T * dt);
+            assert!((v.y.atan2(v.x) - heading).abs() < 1e-9);
+        }
+    }
+
+    /// RMS position error over a path, predicted every half second with
+    /// the acceleration at the start of the step
+    fn coarse_error(seed: u64, process: UkfProcess) -> Result<T, KalmanError> {
+        let (every, mut config) = (50, KalmanConfig::default());
+        config.ukf.process = process;
+        let mut ukf = Ukf::new(config);
+        let mut trajectory = Trajectory::new(seed, 5);
+        let start = trajectory.next().unwrap();
+        ukf.init(start.position, start.speed(), start.direction);
+        let (mut acc, mut sum, mut count) = (Vector3::zeros(), 0., 0.);
+        for point in trajectory {
+            if point.step % every == 1 {
+                acc = point.acceleration;
+            }
+            if point.step % every != 0 {
+                continue;
+            }
+            ukf.prediction(&acc, Some(every as T * config.dt))?;
+            if point.step % 300 == 0 {
+                ukf.correction(&point.position)?;
+            }
+            let error = Vector3::from_column_slice(&ukf.get_state()[..3]) - point.position;
+            sum += error.norm_squared();
+            count += 1.;
+        }
+        Ok((sum / count).sqrt())
+    }
+
+    /// Seed 6 keeps clear of the speed limits, where the acceleration
+    /// flips every step and half second samples of it mean nothing
+    #[test]
+    fn curvilinear_tracks_turns_closer_than_linear() -> Result<(), KalmanError> {
+        let linear = coarse_error(6, UkfProcess::Linear)?;
+        let curvilinear = coarse_error(6, UkfProcess::Curvilinear)?;
+        assert!(
+            curvilinear < 0.2 * linear,
+            "{curvilinear} m against {linear} m"
+        );
+        Ok(())
+    }
+}
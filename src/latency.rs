@@ -0,0 +1,143 @@
+use crate::error::KalmanError;
+use crate::types::T;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const BUCKET_NS: u64 = 100;
+const BUCKETS: usize = 10_000; // up to 1ms, anything slower lands in the last bucket
+
+/// Histogram of the `MSG_END` -> position sent response time.
+#[derive(Debug)]
+pub struct Latency {
+    buckets: Vec<u64>,
+    count: u64,
+    min: Duration,
+    max: Duration,
+    output: Option<BufWriter<File>>,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::new()
+    }
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        Latency {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            output: None,
+        }
+    }
+
+    /// `output` receives every raw sample, one value in nanoseconds per line.
+    pub fn with_output(mut self, output: &Path) -> Result<Latency, KalmanError> {
+        self.output = Some(BufWriter::new(File::create(output)?));
+        Ok(self)
+    }
+
+    pub fn record(&mut self, sample: Duration) -> Result<(), KalmanError> {
+        let ns = sample.as_nanos() as u64;
+        let bucket = ((ns / BUCKET_NS) as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+        if let Some(output) = &mut self.output {
+            writeln!(output, "{ns}")?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), KalmanError> {
+        if let Some(output) = &mut self.output {
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Upper bound of the bucket holding the `p` quantile, `p` in [0, 1],
+    /// the max past the last bucket and zero without samples
+    pub fn percentile(&self, p: T) -> Duration {
+        let rank = ((p * self.count as T).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank && i == BUCKETS - 1 {
+                return self.max;
+            }
+            if seen >= rank {
+                let upper = Duration::from_nanos((i as u64 + 1) * BUCKET_NS);
+                return upper.clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "Latency: no reply sent");
+        }
+        write!(
+            f,
+            "Latency over {} replies: min {:?}, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.count,
+            self.min,
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latency(samples_ns: &[u64]) -> Result<Latency, KalmanError> {
+        let mut latency = Latency::new();
+        for ns in samples_ns {
+            latency.record(Duration::from_nanos(*ns))?;
+        }
+        Ok(latency)
+    }
+
+    #[test]
+    fn empty_histogram_is_zero() {
+        let latency = Latency::new();
+        assert_eq!(latency.count(), 0);
+        assert_eq!(latency.percentile(0.5), Duration::ZERO);
+        assert_eq!(latency.percentile(1.), Duration::ZERO);
+    }
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds_within_min_max() -> Result<(), KalmanError> {
+        let latency = latency(&[150, 250, 350, 450])?;
+        assert_eq!(latency.percentile(0.), Duration::from_nanos(200));
+        assert_eq!(latency.percentile(0.5), Duration::from_nanos(300));
+        assert_eq!(latency.percentile(1.), Duration::from_nanos(450));
+        Ok(())
+    }
+
+    #[test]
+    fn slow_samples_report_the_max() -> Result<(), KalmanError> {
+        let latency = latency(&[150, 5_000_000, 7_000_000])?;
+        assert_eq!(latency.percentile(0.), Duration::from_nanos(200));
+        assert_eq!(latency.percentile(0.5), Duration::from_millis(7));
+        assert_eq!(latency.percentile(1.), Duration::from_millis(7));
+        Ok(())
+    }
+}
@@ -1,11 +1,20 @@
 pub mod client;
 pub mod error;
+pub mod estimator;
 pub mod gui;
 pub mod kalman;
+pub mod latency;
 pub mod log;
 pub mod message;
 pub mod orchestrator;
 pub mod plot_data;
+pub mod recorder;
+pub mod replay;
+pub mod rng;
+pub mod score;
+pub mod simulator;
+pub mod trajectory;
+pub mod transport;
 pub mod types;
 
 pub use gui::GuiView;
@@ -1,27 +1,35 @@
 use crate::message::Message;
 use crate::types::T;
-use color_print::cprintln;
+use color_print::cformat;
 
-pub fn log_in_message(message: &Message) {
+/// Verbose line of a received message, colored
+pub fn format_in_message(message: &Message) -> String {
     match message {
-        Message::End => cprintln!("End"),
-        Message::Start => cprintln!("Start"),
-        Message::Generation => cprintln!("Trajectory Generated!"),
-        Message::Goodbye => cprintln!("recv: Goodbye"),
+        Message::End => cformat!("End"),
+        Message::Start => cformat!("Start"),
+        Message::Generation => cformat!("Trajectory Generated!"),
+        Message::Goodbye => cformat!("recv: Goodbye"),
         Message::TruePosition(v) => {
-            cprintln!("<bright-yellow>True Pos: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z)
+            cformat!(
+                "<bright-yellow>True Pos: {:.4} {:.4} {:.4}</>",
+                v.x,
+                v.y,
+                v.z
+            )
         }
-        Message::Position(v) => cprintln!("<magenta>Pos: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z),
-        Message::Direction(v) => cprintln!("<cyan>Dir: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z),
-        Message::Acceleration(v) => cprintln!("<green>Acc: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z),
-        Message::Speed(s) => cprintln!("Speed: {:.4}", s),
+        Message::Position(v) => cformat!("<magenta>Pos: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z),
+        Message::Direction(v) => cformat!("<cyan>Dir: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z),
+        Message::Acceleration(v) => cformat!("<green>Acc: {:.4} {:.4} {:.4}</>", v.x, v.y, v.z),
+        Message::Speed(s) => cformat!("Speed: {:.4}", s),
     }
 }
-pub fn log_filer_pos(state: &[T]) {
-    cprintln!(
+
+/// Verbose line of a sent position, colored
+pub fn format_filter_pos(state: &[T]) -> String {
+    cformat!(
         "<blue>Kalman pos: {:.4} {:.4} {:.4}</>",
         state[0],
         state[1],
         state[2]
-    );
+    )
 }
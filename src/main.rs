@@ -1,12 +1,32 @@
+use kalman::client::{Client, DEFAULT_PORT};
+use kalman::error::KalmanError;
+use kalman::estimator::Estimator;
+use kalman::kalman::accel::{AccelKalman, AccelModel};
+use kalman::kalman::adaptive::Adapt;
+use kalman::kalman::bias::BiasKalman;
+use kalman::kalman::imm::Imm;
+use kalman::kalman::ins::Ins;
+use kalman::kalman::particle::ParticleFilter;
+use kalman::kalman::rts::write_track;
+use kalman::kalman::ukf::{Ukf, UkfProcess};
+use kalman::kalman::{Kalman, KalmanConfig};
+use kalman::recorder::Recorder;
+use kalman::replay::ReplayTransport;
+use kalman::transport::Transport;
 use kalman::{GuiView, Orchestrator, PlotData};
 
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use std::thread::{self, JoinHandle};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
-use color_print::cprintln;
+use clap::{Parser, ValueEnum};
+use color_print::ceprintln;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None, name="ft_kalman")]
@@ -26,10 +46,371 @@ pub(crate) struct Args {
     /// GUI with only print the last 20 min of the trajectory
     #[arg(short, long)]
     follow: bool,
+
+    /// Abort when the distance to the true position exceeds this value (m)
+    #[arg(short, long)]
+    max_delta: Option<f64>,
+
+    /// Write every reply latency (ns) to this file
+    #[arg(short, long)]
+    latency_out: Option<PathBuf>,
+
+    /// Simulator host
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Simulator port
+    #[arg(short, long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+
+    /// If --port stays quiet, also try the ports up to this distance from it,
+    /// closest first. Each gets an empty datagram, and READY only if it is
+    /// not refused
+    #[arg(long, default_value_t = 0)]
+    probe: u16,
+
+    /// Record the session (received datagrams and sent positions) to this file
+    #[arg(short, long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Run the filter on a recorded session instead of the simulator
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Replay at the recorded pace instead of as fast as possible
+    #[arg(long, requires = "replay")]
+    realtime: bool,
+
+    /// Write the replayed estimated positions to this file instead of stdout
+    #[arg(short, long, requires = "replay")]
+    output: Option<PathBuf>,
+
+    /// Write the RTS-smoothed track of the replayed session to this file,
+    /// one `t x y z vx vy vz` line per step, t in seconds since the first
+    /// block, followed by the sigmas
+    #[arg(long, requires = "replay")]
+    smooth: Option<PathBuf>,
+
+    /// Also estimate each step this many steps late with a fixed-lag
+    /// smoother, shown next to the real-time estimate in the GUI
+    #[arg(long)]
+    lag: Option<usize>,
+
+    /// Write the fixed-lag estimate of every step to this file, in the
+    /// --smooth format
+    #[arg(long, requires = "lag")]
+    lag_out: Option<PathBuf>,
+
+    /// Fuse every DIRECTION and SPEED as a velocity measurement (imu_sim
+    /// sends SPEED in the first block only)
+    #[arg(long)]
+    fuse_direction: bool,
+
+    /// Only score with the TRUE POSITION of the later blocks, without fusing
+    /// it as a measurement
+    #[arg(long)]
+    no_fuse_truth: bool,
+
+    /// Filter algorithm
+    #[arg(long, value_enum, default_value_t = Filter::Kalman)]
+    filter: Filter,
+
+    #[command(flatten)]
+    tuning: Tuning,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+// Overrides of the `KalmanConfig` defaults
+#[derive(clap::Args)]
+#[command(next_help_heading = "Filter tuning")]
+struct Tuning {
+    /// File of `key = value` lines, keys being the long flags below with
+    /// underscores
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Time step of unstamped messages (s)
+    #[arg(long)]
+    dt: Option<f64>,
+
+    /// Accelerometer noise sigma (m/s^2)
+    #[arg(long)]
+    s_acc: Option<f64>,
+
+    /// GPS noise sigma (m)
+    #[arg(long)]
+    s_gps: Option<f64>,
+
+    /// Initial position sigma (m)
+    #[arg(long)]
+    init_pos_sigma: Option<f64>,
+
+    /// Initial velocity sigma (m/s)
+    #[arg(long)]
+    init_vel_sigma: Option<f64>,
+
+    /// SPEED noise sigma (m/s)
+    #[arg(long)]
+    s_speed: Option<f64>,
+
+    /// DIRECTION pitch and yaw noise sigma, velocity heading filters (rad),
+    /// inflated like --s-acc: the sensor's own 0.01 lets its noise steer the
+    /// velocity
+    #[arg(long)]
+    s_heading: Option<f64>,
+
+    /// Initial accelerometer bias sigma, bias filter (m/s^2)
+    #[arg(long)]
+    s_bias_init: Option<f64>,
+
+    /// Accelerometer bias random walk, bias filter (m/s^2/sqrt(s))
+    #[arg(long)]
+    s_bias_walk: Option<f64>,
+
+    /// DIRECTION noise sigma, INS filter (rad)
+    #[arg(long)]
+    s_attitude: Option<f64>,
+
+    /// Angular rate random walk, INS filter (rad/s/sqrt(s))
+    #[arg(long)]
+    s_rate: Option<f64>,
+
+    /// Initial angular rate sigma, INS filter (rad/s)
+    #[arg(long)]
+    s_rate_init: Option<f64>,
+
+    /// Deprecated, ignored: the innovation covariance is factorised instead
+    /// of pseudo-inverted
+    #[arg(long, hide = true)]
+    pinv_tolerance: Option<f64>,
+
+    /// Estimate R, Q or both (rq) online from the GPS innovations, Kalman only
+    #[arg(long)]
+    adapt: Option<Adapt>,
+
+    /// GPS fixes the adaptation averages over
+    #[arg(long)]
+    adapt_window: Option<usize>,
+
+    /// Lower bound of the adapted GPS sigma (m)
+    #[arg(long)]
+    adapt_r_min: Option<f64>,
+
+    /// Upper bound of the adapted GPS sigma (m)
+    #[arg(long)]
+    adapt_r_max: Option<f64>,
+
+    /// Lower bound of the adapted Q, relative to the configured one
+    #[arg(long)]
+    adapt_q_min: Option<f64>,
+
+    /// Upper bound of the adapted Q, relative to the configured one
+    #[arg(long)]
+    adapt_q_max: Option<f64>,
+
+    /// Freeze the adapted noise after this many GPS fixes
+    #[arg(long)]
+    adapt_freeze_after: Option<usize>,
+
+    /// Reject the GPS fixes whose NIS falls outside this confidence level
+    #[arg(long)]
+    gate_confidence: Option<f64>,
+
+    /// Accept the next GPS fix after this many consecutive rejections
+    #[arg(long)]
+    gate_reset_after: Option<usize>,
+
+    /// Process function of the UKF: linear or curvilinear
+    #[arg(long)]
+    ukf_process: Option<UkfProcess>,
+
+    /// Sigma point spread of the UKF
+    #[arg(long)]
+    ukf_alpha: Option<f64>,
+
+    /// Prior distribution weight of the UKF, 2 for a Gaussian
+    #[arg(long)]
+    ukf_beta: Option<f64>,
+
+    /// Secondary sigma point scaling of the UKF
+    #[arg(long, allow_negative_numbers = true)]
+    ukf_kappa: Option<f64>,
+
+    /// Motion model of the acceleration state filter: constant or singer
+    #[arg(long)]
+    accel_model: Option<AccelModel>,
+
+    /// White jerk of the constant acceleration model (m/s^3/sqrt(Hz))
+    #[arg(long)]
+    accel_jerk: Option<f64>,
+
+    /// Correlation time of the Singer acceleration (s)
+    #[arg(long)]
+    accel_tau: Option<f64>,
+
+    /// Steady state sigma of the Singer acceleration (m/s^2)
+    #[arg(long)]
+    accel_sigma: Option<f64>,
+
+    /// IMM model switch probabilities per step, 9 numbers row-major from
+    /// cruise, manoeuvre (constant acceleration) and turn to the same
+    #[arg(long)]
+    imm_markov: Option<String>,
+
+    /// Particles of the particle filter
+    #[arg(long)]
+    pf_particles: Option<usize>,
+
+    /// Resample once the effective sample size falls below this fraction of
+    /// the particles
+    #[arg(long)]
+    pf_ess: Option<f64>,
+
+    /// Seed of the particle filter
+    #[arg(long)]
+    pf_seed: Option<u64>,
+
+    /// Degrees of freedom of the Student-t GPS error, inf for a Gaussian
+    #[arg(long)]
+    pf_dof: Option<f64>,
+}
+
+impl Tuning {
+    /// The config file, if any, then the flags
+    fn config(&self) -> Result<KalmanConfig, KalmanError> {
+        let mut config = match &self.config {
+            Some(path) => KalmanConfig::load(path)?,
+            None => KalmanConfig::default(),
+        };
+        let flags = [
+            ("dt", self.dt),
+            ("s_acc", self.s_acc),
+            ("s_gps", self.s_gps),
+            ("init_pos_sigma", self.init_pos_sigma),
+            ("init_vel_sigma", self.init_vel_sigma),
+            ("s_speed", self.s_speed),
+            ("s_heading", self.s_heading),
+            ("s_bias_init", self.s_bias_init),
+            ("s_bias_walk", self.s_bias_walk),
+            ("s_attitude", self.s_attitude),
+            ("s_rate", self.s_rate),
+            ("s_rate_init", self.s_rate_init),
+            ("pinv_tolerance", self.pinv_tolerance),
+            ("adapt_r_min", self.adapt_r_min),
+            ("adapt_r_max", self.adapt_r_max),
+            ("adapt_q_min", self.adapt_q_min),
+            ("adapt_q_max", self.adapt_q_max),
+            ("ukf_alpha", self.ukf_alpha),
+            ("ukf_beta", self.ukf_beta),
+            ("ukf_kappa", self.ukf_kappa),
+            ("accel_jerk", self.accel_jerk),
+            ("accel_tau", self.accel_tau),
+            ("accel_sigma", self.accel_sigma),
+            ("pf_ess", self.pf_ess),
+            ("pf_dof", self.pf_dof),
+        ];
+        for (key, value) in flags {
+            if let Some(value) = value {
+                config.set(key, &value.to_string())?;
+            }
+        }
+        if let Some(adapt) = self.adapt {
+            config.adaptive.mode = adapt;
+        }
+        if let Some(window) = self.adapt_window {
+            config.set("adapt_window", &window.to_string())?;
+        }
+        if let Some(after) = self.adapt_freeze_after {
+            config.set("adapt_freeze_after", &after.to_string())?;
+        }
+        if let Some(confidence) = self.gate_confidence {
+            config.set("gate_confidence", &confidence.to_string())?;
+        }
+        if let Some(after) = self.gate_reset_after {
+            config.set("gate_reset_after", &after.to_string())?;
+        }
+        if let Some(particles) = self.pf_particles {
+            config.set("pf_particles", &particles.to_string())?;
+        }
+        if let Some(seed) = self.pf_seed {
+            config.set("pf_seed", &seed.to_string())?;
+        }
+        if let Some(process) = self.ukf_process {
+            config.ukf.process = process;
+        }
+        if let Some(model) = self.accel_model {
+            config.accel.model = model;
+        }
+        if let Some(markov) = &self.imm_markov {
+            config.set("imm_markov", markov)?;
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Filter {
+    /// Linear filter on world-frame accelerations
+    Kalman,
+    /// Attitude-aware filter on body-frame accelerations
+    Ins,
+    /// Linear filter estimating the accelerometer bias
+    Bias,
+    /// Unscented filter, see --ukf-process
+    Ukf,
+    /// Linear filter with the acceleration as state, see --accel-model
+    Accel,
+    /// Interacting multiple model: cruise, constant acceleration and turn
+    Imm,
+    /// Bootstrap particle filter
+    Particle,
+}
+
+impl Filter {
+    fn build(self, config: KalmanConfig) -> Box<dyn Estimator + Send> {
+        match self {
+            Filter::Kalman => Box::new(Kalman::new(config)),
+            Filter::Ins => Box::new(Ins::new(config)),
+            Filter::Bias => Box::new(BiasKalman::new(config)),
+            Filter::Ukf => Box::new(Ukf::new(config)),
+            Filter::Accel => Box::new(AccelKalman::new(config)),
+            Filter::Imm => Box::new(Imm::new(config)),
+            Filter::Particle => Box::new(ParticleFilter::new(config)),
+        }
+    }
+}
+
+/// Smooth the steps kept by `filter` into `path`
+fn write_smoothed(filter: &dyn Estimator, path: &Path) -> Result<(), KalmanError> {
+    let history = filter
+        .history()
+        .ok_or_else(|| KalmanError::Config("the filter keeps no history".into()))?;
+    write_track(&history.smooth()?, BufWriter::new(File::create(path)?))
+}
+
+fn transport(args: &Args) -> Result<Box<dyn Transport + Send>, Box<dyn Error>> {
+    if let Some(recording) = &args.replay {
+        return Ok(Box::new(ReplayTransport::open(
+            recording,
+            args.output.as_deref(),
+            args.realtime,
+        )?));
+    }
+
+    let server = (args.host.as_str(), args.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or("Could not resolve the simulator address")?;
+
+    let mut client = Client::new(server, args.probe)?;
+    if let Some(path) = &args.record {
+        client = client.with_recorder(Recorder::create(path)?);
+    }
+    Ok(Box::new(client))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+    let config = args.tuning.config()?;
     let start = SystemTime::now().duration_since(UNIX_EPOCH)?;
 
     let plot_data = if args.gui {
@@ -38,17 +419,50 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         None
     };
 
+    let filter: Box<dyn Estimator + Send> = match args.filter {
+        Filter::Kalman => {
+            let mut filter = Kalman::new(config);
+            if args.smooth.is_some() {
+                filter = filter.with_history();
+            }
+            if let Some(lag) = args.lag {
+                filter = filter.with_fixed_lag(lag);
+            }
+            Box::new(filter)
+        }
+        _ if args.smooth.is_some() || args.lag.is_some() => {
+            return Err("--smooth and --lag need --filter kalman".into());
+        }
+        filter => filter.build(config),
+    };
+
     let mut orchestrator = Orchestrator::new(
-        "127.0.0.1:4242",
+        transport(&args)?,
         plot_data.clone(),
         args.throttle,
         args.verbose,
         args.follow,
-    )?;
+    )
+    .with_max_delta(args.max_delta)
+    .with_direction_fusion(args.fuse_direction)
+    .with_truth_fusion(!args.no_fuse_truth)
+    .with_filter(filter);
+    if let Some(path) = &args.latency_out {
+        orchestrator = orchestrator.with_latency_out(path)?;
+    }
+    if let Some(path) = &args.lag_out {
+        orchestrator = orchestrator.with_lag_out(path)?;
+    }
+    let smooth = args.smooth.clone();
+    let positions_to_stdout = args.replay.is_some() && args.output.is_none();
 
     let thread_join_handle: JoinHandle<()> = thread::spawn(move || {
-        if let Err(err) = orchestrator.run() {
-            cprintln!("<red>{err}</>");
+        let result = orchestrator.run().and_then(|()| match &smooth {
+            Some(path) => write_smoothed(orchestrator.filter(), path),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            ceprintln!("<red>{err}</>");
         }
     });
 
@@ -59,6 +473,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = thread_join_handle.join();
 
     let end = SystemTime::now().duration_since(UNIX_EPOCH)?;
-    println!("Finished in {}ms", (end - start).as_millis());
+    let elapsed = (end - start).as_millis();
+    if positions_to_stdout {
+        eprintln!("Finished in {elapsed}ms");
+    } else {
+        println!("Finished in {elapsed}ms");
+    }
     Ok(())
 }
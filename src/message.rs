@@ -1,10 +1,19 @@
 use crate::{
     error::KalmanError,
-    types::{string_of_vector3, Vector3, T},
+    types::{T, Vector3, string_of_vector3},
 };
 use nalgebra::vector;
-use std::{fmt, str::Split};
+use std::fmt;
+use std::slice::Split;
 
+pub const HEADER_LEN: usize = 14;
+
+const START: &[u8] = b"MSG_START";
+const END: &[u8] = b"MSG_END";
+const GOODBYE: &[u8] = b"GOODBYE.";
+const GENERATION: &[u8] = b"Trajectory Generated!\nSending Info. . .\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Message {
     Start,
     End,
@@ -17,6 +26,19 @@ pub enum Message {
     Acceleration(Vector3),
 }
 
+/// Simulation time of the `[HH:MM:SS.mmm]` header of data datagrams.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    millis: u64,
+}
+
+/// A datagram: its message and, for data messages, the header timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Packet {
+    pub timestamp: Option<Timestamp>,
+    pub message: Message,
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -32,38 +54,362 @@ impl fmt::Display for Message {
         }
     }
 }
+
+impl Message {
+    /// Label of the data messages, `None` for control messages
+    pub fn label(&self) -> Option<&'static str> {
+        match self {
+            Message::TruePosition(_) => Some("TRUE POSITION"),
+            Message::Position(_) => Some("POSITION"),
+            Message::Acceleration(_) => Some("ACCELERATION"),
+            Message::Direction(_) => Some("DIRECTION"),
+            Message::Speed(_) => Some("SPEED"),
+            Message::Start | Message::End | Message::Generation | Message::Goodbye => None,
+        }
+    }
+
+    /// Datagram as the simulator sends it, `timestamp` is only written for
+    /// data messages.
+    pub fn encode(&self, timestamp: Timestamp) -> Vec<u8> {
+        match self {
+            Message::Start => START.to_vec(),
+            Message::End => END.to_vec(),
+            Message::Goodbye => GOODBYE.to_vec(),
+            Message::Generation => GENERATION.to_vec(),
+            Message::Speed(s) => format!("{timestamp}SPEED\n{s}").into_bytes(),
+            Message::TruePosition(v)
+            | Message::Position(v)
+            | Message::Direction(v)
+            | Message::Acceleration(v) => {
+                let label = self.label().unwrap_or_default();
+                format!("{timestamp}{label}\n{}\n{}\n{}", v.x, v.y, v.z).into_bytes()
+            }
+        }
+    }
+}
+
 impl TryFrom<&str> for Message {
     type Error = KalmanError;
 
     fn try_from(msg: &str) -> Result<Message, Self::Error> {
-        fn vec_of_it(it: &mut Split<'_, char>) -> Result<Vector3, KalmanError> {
-            match (it.next(), it.next(), it.next()) {
-                (Some(x), Some(y), Some(z)) => {
-                    Ok(vector![x.parse::<T>()?, y.parse::<T>()?, z.parse::<T>()?])
+        Packet::parse(msg.as_bytes()).map(|packet| packet.message)
+    }
+}
+
+impl Timestamp {
+    /// Last time the two digit hours of the header can hold
+    pub const MAX: Timestamp = Timestamp {
+        millis: 100 * 3_600_000 - 1,
+    };
+
+    pub fn from_millis(millis: u64) -> Timestamp {
+        Timestamp { millis }
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.millis
+    }
+
+    pub fn as_secs(&self) -> T {
+        self.millis as T / 1000.
+    }
+
+    /// Parse `[HH:MM:SS.mmm]`, `None` if `header` is not one
+    pub fn parse(header: &[u8]) -> Option<Timestamp> {
+        fn number(digits: &[u8]) -> Option<u64> {
+            digits.iter().try_fold(0, |acc, d| {
+                d.is_ascii_digit().then(|| acc * 10 + (d - b'0') as u64)
+            })
+        }
+        match header {
+            [b'[', h @ .., b']'] if h.len() == HEADER_LEN - 2 => {
+                if h[2] != b':' || h[5] != b':' || h[8] != b'.' {
+                    return None;
                 }
-                _ => Err(KalmanError::Parsing("in vec_of_it".into())),
+                let hours = number(&h[0..2])?;
+                let minutes = number(&h[3..5]).filter(|m| *m < 60)?;
+                let seconds = number(&h[6..8]).filter(|s| *s < 60)?;
+                let millis = number(&h[9..12])?;
+                Some(Timestamp::from_millis(
+                    ((hours * 60 + minutes) * 60 + seconds) * 1000 + millis,
+                ))
             }
+            _ => None,
         }
-        match msg {
-            "MSG_START" => Ok(Message::Start),
-            "MSG_END" => Ok(Message::End),
-            "GOODBYE." => Ok(Message::Goodbye),
-            "Trajectory Generated!\nSending Info. . .\n" => Ok(Message::Generation),
-            message => {
-                let mut it = message.split_at(14).1.split('\n');
-                match it.next() {
-                    Some("POSITION") => Ok(Message::Position(vec_of_it(&mut it)?)),
-                    Some("TRUE POSITION") => Ok(Message::TruePosition(vec_of_it(&mut it)?)),
-                    Some("ACCELERATION") => Ok(Message::Acceleration(vec_of_it(&mut it)?)),
-                    Some("DIRECTION") => Ok(Message::Direction(vec_of_it(&mut it)?)),
-                    Some("SPEED") => Ok(Message::Speed(
-                        it.next()
-                            .ok_or(KalmanError::Parsing(message.into()))?
-                            .parse::<T>()?,
-                    )),
-                    _ => Err(KalmanError::Parsing(message.into())),
-                }
+    }
+}
+
+/// Clamped to `Timestamp::MAX`, so that the header always parses back
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = self.millis.min(Timestamp::MAX.millis);
+        write!(
+            f,
+            "[{:02}:{:02}:{:02}.{:03}]",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        )
+    }
+}
+
+impl From<Message> for Packet {
+    fn from(message: Message) -> Packet {
+        Packet {
+            timestamp: None,
+            message,
+        }
+    }
+}
+
+impl Packet {
+    /// Inverse of `parse` for data packets with a timestamp (below 100 h) and
+    /// control packets without one. A missing data timestamp is written as 0,
+    /// a later one as `Timestamp::MAX`.
+    pub fn encode(&self) -> Vec<u8> {
+        self.message.encode(self.timestamp.unwrap_or_default())
+    }
+
+    /// Parse a raw datagram. Never panics, whatever the input.
+    pub fn parse(datagram: &[u8]) -> Result<Packet, KalmanError> {
+        let control = match datagram {
+            START => Some(Message::Start),
+            END => Some(Message::End),
+            GOODBYE => Some(Message::Goodbye),
+            GENERATION => Some(Message::Generation),
+            _ => None,
+        };
+        if let Some(message) = control {
+            return Ok(message.into());
+        }
+
+        let Some((header, body)) = datagram.split_at_checked(HEADER_LEN) else {
+            return Err(KalmanError::UnknownMessage { raw: raw(datagram) });
+        };
+        let timestamp = Timestamp::parse(header)
+            .ok_or_else(|| KalmanError::InvalidHeader { raw: raw(datagram) })?;
+
+        let mut fields = body.split(is_newline as fn(&u8) -> bool);
+        let label = fields.next().unwrap_or_default();
+        let mut reader = FieldReader {
+            fields,
+            message: "",
+            datagram,
+        };
+        let message = match label {
+            b"POSITION" => Message::Position(reader.vector("POSITION")?),
+            b"TRUE POSITION" => Message::TruePosition(reader.vector("TRUE POSITION")?),
+            b"ACCELERATION" => Message::Acceleration(reader.vector("ACCELERATION")?),
+            b"DIRECTION" => Message::Direction(reader.vector("DIRECTION")?),
+            b"SPEED" => Message::Speed(reader.scalar("SPEED")?),
+            _ => return Err(KalmanError::UnknownMessage { raw: raw(datagram) }),
+        };
+        reader.end()?;
+        Ok(Packet {
+            timestamp: Some(timestamp),
+            message,
+        })
+    }
+}
+
+fn is_newline(c: &u8) -> bool {
+    *c == b'\n'
+}
+
+/// Printable form of a datagram for error messages
+fn raw(datagram: &[u8]) -> String {
+    datagram.escape_ascii().to_string()
+}
+
+/// Line by line reader of a data message body.
+struct FieldReader<'a> {
+    fields: Split<'a, u8, fn(&u8) -> bool>,
+    message: &'static str,
+    datagram: &'a [u8],
+}
+
+impl FieldReader<'_> {
+    fn float(&mut self, field: &'static str) -> Result<T, KalmanError> {
+        let value = self
+            .fields
+            .next()
+            .ok_or_else(|| KalmanError::MissingField {
+                message: self.message,
+                field,
+                raw: raw(self.datagram),
+            })?;
+        str::from_utf8(value)
+            .ok()
+            .and_then(|v| v.trim().parse::<T>().ok())
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| KalmanError::InvalidField {
+                message: self.message,
+                field,
+                value: raw(value),
+                raw: raw(self.datagram),
+            })
+    }
+
+    fn vector(&mut self, message: &'static str) -> Result<Vector3, KalmanError> {
+        self.message = message;
+        Ok(vector![
+            self.float("x")?,
+            self.float("y")?,
+            self.float("z")?
+        ])
+    }
+
+    fn scalar(&mut self, message: &'static str) -> Result<T, KalmanError> {
+        self.message = message;
+        self.float("value")
+    }
+
+    /// Only empty lines may follow the fields
+    fn end(&mut self) -> Result<(), KalmanError> {
+        if self.fields.all(|f| f.trim_ascii().is_empty()) {
+            return Ok(());
+        }
+        Err(KalmanError::TrailingData {
+            message: self.message,
+            raw: raw(self.datagram),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLOATS: [T; 8] = [
+        0.,
+        -1.5,
+        0.1 + 0.2,
+        1e-300,
+        -6.02e23,
+        T::MIN_POSITIVE,
+        T::MAX,
+        -123456.789,
+    ];
+
+    fn messages() -> Vec<Message> {
+        let mut messages = vec![
+            Message::Start,
+            Message::End,
+            Message::Generation,
+            Message::Goodbye,
+        ];
+        for (i, &f) in FLOATS.iter().enumerate() {
+            let v = vector![
+                f,
+                -FLOATS[(i + 1) % FLOATS.len()],
+                FLOATS[(i + 2) % FLOATS.len()]
+            ];
+            messages.extend([
+                Message::TruePosition(v),
+                Message::Speed(f),
+                Message::Position(v),
+                Message::Direction(v),
+                Message::Acceleration(v),
+            ]);
+        }
+        messages
+    }
+
+    #[test]
+    fn decode_encode_round_trips() -> Result<(), KalmanError> {
+        // Start of the session and the last timestamp below 100 h
+        let timestamps = [0, 12, 3_723_456, 359_999_999].map(Timestamp::from_millis);
+        for message in messages() {
+            for timestamp in timestamps {
+                let packet = Packet {
+                    timestamp: message.label().map(|_| timestamp),
+                    message,
+                };
+                assert_eq!(Packet::parse(&packet.encode())?, packet);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parses_exponent_fields() -> Result<(), KalmanError> {
+        let packet = Packet::parse(b"[00:00:01.250]ACCELERATION\n-1.5e-3\n2E2\n+4e0")?;
+        assert_eq!(packet.timestamp, Some(Timestamp::from_millis(1250)));
+        assert_eq!(
+            packet.message,
+            Message::Acceleration(vector![-1.5e-3, 200., 4.])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn timestamp_display_parses_back() {
+        for millis in [0, 999, 59_999, 3_599_999, 359_999_999] {
+            let timestamp = Timestamp::from_millis(millis);
+            let header = timestamp.to_string();
+            assert_eq!(header.len(), HEADER_LEN);
+            assert_eq!(Timestamp::parse(header.as_bytes()), Some(timestamp));
+        }
+    }
+
+    #[test]
+    fn bad_datagrams_report_their_error() {
+        let parse = |datagram: &[u8]| Packet::parse(datagram).unwrap_err();
+        assert!(matches!(parse(b""), KalmanError::UnknownMessage { .. }));
+        assert!(matches!(
+            parse(b"MSG_STAR"),
+            KalmanError::UnknownMessage { .. }
+        ));
+        assert!(matches!(
+            parse(b"[00:00:00.000]VELOCITY\n1\n2\n3"),
+            KalmanError::UnknownMessage { .. }
+        ));
+        assert!(matches!(
+            parse(b"[00:00:0x.000]POSITION\n1\n2\n3"),
+            KalmanError::InvalidHeader { .. }
+        ));
+        assert!(matches!(
+            parse(b"[00:61:00.000]POSITION\n1\n2\n3"),
+            KalmanError::InvalidHeader { .. }
+        ));
+        assert!(matches!(
+            parse(b"[00:00:00.000]POSITION\n1\n2"),
+            KalmanError::MissingField {
+                message: "POSITION",
+                field: "z",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(b"[00:00:00.000]SPEED\nfast"),
+            KalmanError::InvalidField {
+                message: "SPEED",
+                field: "value",
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse(b"[00:00:00.000]DIRECTION\n1\nNaN\n3"),
+            KalmanError::InvalidField { field: "y", .. }
+        ));
+        assert!(matches!(
+            parse(b"[00:00:00.000]ACCELERATION\n1\n2\n3\n4"),
+            KalmanError::TrailingData {
+                message: "ACCELERATION",
+                ..
             }
+        ));
+    }
+
+    #[test]
+    fn timestamps_past_100_hours_are_clamped() -> Result<(), KalmanError> {
+        let message = Message::Speed(1.);
+        for millis in [Timestamp::MAX.as_millis() + 1, 1_000 * 3_600_000] {
+            let encoded = message.encode(Timestamp::from_millis(millis));
+            assert!(encoded.starts_with(b"[99:59:59.999]"));
+            assert_eq!(Packet::parse(&encoded)?.timestamp, Some(Timestamp::MAX));
         }
+        Ok(())
     }
 }
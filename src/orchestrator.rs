@@ -1,96 +1,252 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::client::Client;
 use crate::error::KalmanError;
-use crate::kalman::Kalman;
-use crate::log::{log_filer_pos, log_in_message};
-use crate::message::Message;
+use crate::estimator::Estimator;
+use crate::kalman::rts::write_step;
+use crate::kalman::{Kalman, KalmanConfig};
+use crate::latency::Latency;
+use crate::log::{format_filter_pos, format_in_message};
+use crate::message::{Message, Timestamp};
 use crate::plot_data::PlotData;
+use crate::score::Score;
+use crate::transport::Transport;
 use crate::types::T;
 
 pub struct Orchestrator {
-    client: Client,
-    filter: Kalman,
+    transport: Box<dyn Transport + Send>,
+    filter: Box<dyn Estimator + Send>,
     plot_data: Option<Arc<Mutex<PlotData>>>,
+    score: Score,
+    latency: Latency,
+    lag_out: Option<BufWriter<File>>,
     throttle: u64,
     verbose: bool,
     follow: bool,
+    fuse_direction: bool,
+    fuse_truth: bool,
+    last_acc: Option<Timestamp>,
 }
 
 impl Orchestrator {
     pub fn new(
-        server_addr: &'static str,
+        transport: Box<dyn Transport + Send>,
         plot_data: Option<Arc<Mutex<PlotData>>>,
         throttle: u64,
         verbose: bool,
         follow: bool,
-    ) -> Result<Orchestrator, KalmanError> {
-        Ok(Orchestrator {
-            client: Client::new(server_addr)?,
-            filter: Kalman::new(),
+    ) -> Orchestrator {
+        Orchestrator {
+            transport,
+            filter: Box::new(Kalman::new(KalmanConfig::default())),
             plot_data,
+            score: Score::new(None),
+            latency: Latency::new(),
+            lag_out: None,
             throttle,
             verbose,
             follow,
-        })
+            fuse_direction: false,
+            fuse_truth: true,
+            last_acc: None,
+        }
+    }
+
+    /// Report the positions further than `max_delta` from the truth
+    pub fn with_max_delta(mut self, max_delta: Option<T>) -> Orchestrator {
+        self.score = Score::new(max_delta);
+        self
+    }
+
+    /// Write every latency sample to `path`
+    pub fn with_latency_out(mut self, path: &Path) -> Result<Orchestrator, KalmanError> {
+        self.latency = self.latency.with_output(path)?;
+        Ok(self)
+    }
+
+    /// Use every DIRECTION (and SPEED) as a velocity measurement, not only
+    /// the initial one.
+    pub fn with_direction_fusion(mut self, fuse_direction: bool) -> Orchestrator {
+        self.fuse_direction = fuse_direction;
+        self
+    }
+
+    /// Write the fixed-lag estimate to `path` after every prediction, in
+    /// the `write_track` format, the first line being the initial step
+    pub fn with_lag_out(mut self, path: &Path) -> Result<Orchestrator, KalmanError> {
+        self.lag_out = Some(BufWriter::new(File::create(path)?));
+        Ok(self)
+    }
+
+    /// Correct the filter with the TRUE POSITION after the first block, as
+    /// by default. Off, the truth only initialises the state and scores the
+    /// later replies. The initial one, set from the truth, is never scored.
+    pub fn with_truth_fusion(mut self, fuse_truth: bool) -> Orchestrator {
+        self.fuse_truth = fuse_truth;
+        self
+    }
+
+    /// Replace the default `Kalman` filter
+    pub fn with_filter(mut self, filter: Box<dyn Estimator + Send>) -> Orchestrator {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> &(dyn Estimator + Send) {
+        self.filter.as_ref()
     }
 
+    /// Runs the session up to the Goodbye, the transport output being
+    /// flushed when it returns, error or not
     pub fn run(&mut self) -> Result<(), KalmanError> {
-        self.client.start()?;
+        let result = self.process();
+        let flushed = self.flush_outputs();
+        if let (Err(_), Err(err)) = (&result, &flushed) {
+            // The session error is the one returned
+            eprintln!("Flushing the output failed: {err}");
+        }
+        result?;
+        flushed?;
+        self.set_done();
+        if self.score.count() > 0 {
+            self.report(&self.score);
+        }
+        if let Some(gate) = self.filter.gate()
+            && gate.threshold().is_some()
+        {
+            self.report(gate);
+        }
+        self.latency.flush()?;
+        self.report(&self.latency);
+        Ok(())
+    }
+
+    fn flush_outputs(&mut self) -> Result<(), KalmanError> {
+        let flushed = self.transport.flush();
+        if let Some(lag_out) = &mut self.lag_out {
+            lag_out.flush()?;
+        }
+        flushed
+    }
+
+    /// Summary or verbose line, on stderr when the positions go to stdout
+    fn report(&self, summary: &dyn Display) {
+        if self.transport.uses_stdout() {
+            eprintln!("{summary}");
+        } else {
+            println!("{summary}");
+        }
+    }
+
+    fn process(&mut self) -> Result<(), KalmanError> {
+        self.transport.start()?;
         self.process_init_msg()?;
         loop {
-            let message = self.client.recv_into_buf()?;
+            let packet = self.transport.recv()?;
+            let received = Instant::now();
             if self.verbose {
-                log_in_message(&message);
+                self.report(&format_in_message(&packet.message));
             }
-            match &message {
-                Message::End => self.send_pos()?,
+            match &packet.message {
+                Message::End => self.send_pos(received)?,
                 Message::Goodbye => break,
-                Message::TruePosition(pos) | Message::Position(pos) => {
+                Message::TruePosition(pos) => {
+                    self.score.set_truth(pos);
+                    self.update_plot_data(Some(pos.as_slice()));
+                    if self.fuse_truth {
+                        self.filter.correction(pos)?;
+                    }
+                }
+                Message::Position(pos) => {
                     self.update_plot_data(Some(pos.as_slice()));
                     self.filter.correction(pos)?;
                 }
                 Message::Acceleration(acc) => {
-                    self.filter.prediction(acc)?;
+                    let Some(dt) = self.step(packet.timestamp) else {
+                        continue;
+                    };
+                    self.filter.prediction(acc, dt)?;
+                    self.emit_lagged()?;
                     if self.follow {
                         self.update_plot_data(None);
                     }
                 }
-                Message::Direction(_)
-                | Message::Start
-                | Message::Speed(_)
-                | Message::Generation => continue,
+                Message::Speed(speed) if self.fuse_direction => {
+                    self.filter.correction_speed(*speed)?
+                }
+                Message::Direction(dir) if self.fuse_direction || self.filter.needs_direction() => {
+                    self.filter.correction_direction(dir)?
+                }
+                Message::Direction(_) | Message::Speed(_) => continue,
+                Message::Start | Message::Generation => continue,
             };
         }
-        self.set_done();
         Ok(())
     }
     fn process_init_msg(&mut self) -> Result<(), KalmanError> {
-        let _start = self.client.recv_into_buf()?;
-        let pos = self.client.recv_into_buf()?;
-        let speed = self.client.recv_into_buf()?;
-        let _acc = self.client.recv_into_buf()?;
-        let dir = self.client.recv_into_buf()?;
-        let _end = self.client.recv_into_buf()?;
-
-        match (pos, speed, dir) {
-            (Message::TruePosition(pos), Message::Speed(speed), Message::Direction(dir)) => {
+        let _start = self.transport.recv()?;
+        let pos = self.transport.recv()?.message;
+        let speed = self.transport.recv()?.message;
+        let acc = self.transport.recv()?;
+        let dir = self.transport.recv()?.message;
+        let _end = self.transport.recv()?;
+        let received = Instant::now();
+
+        match (pos, speed, acc.message, dir) {
+            (
+                Message::TruePosition(pos),
+                Message::Speed(speed),
+                Message::Acceleration(_),
+                Message::Direction(dir),
+            ) => {
+                self.last_acc = acc.timestamp;
                 self.filter.init(pos, speed, dir);
-                self.send_pos()
+                if self.fuse_direction {
+                    // Sets the velocity covariance the later headings rely on.
+                    // The speed is left out: `init` already set it, and under
+                    // the initial covariance its nonlinearity biases the UKF.
+                    self.filter.correction_direction(&dir)?;
+                }
+                self.send_pos(received)
             }
             _ => Err(KalmanError::Parsing("Bad inital messsage".into())),
         }
     }
 
-    fn send_pos(&mut self) -> Result<(), KalmanError> {
+    /// Step of the prediction of an ACCELERATION: the time since the latest
+    /// one, `Some(None)` if either is unstamped, and `None` to skip a
+    /// duplicate or late packet, whose time was already predicted over
+    fn step(&mut self, timestamp: Option<Timestamp>) -> Option<Option<T>> {
+        let (Some(timestamp), Some(latest)) = (timestamp, self.last_acc) else {
+            self.last_acc = timestamp;
+            return Some(None);
+        };
+        let millis = timestamp.as_millis().checked_sub(latest.as_millis())?;
+        if millis == 0 {
+            return None;
+        }
+        self.last_acc = Some(timestamp);
+        Some(Some(millis as T / 1000.))
+    }
+
+    /// `received` is when the `MSG_END` arrived, the throttle sleep is not counted.
+    fn send_pos(&mut self, received: Instant) -> Result<(), KalmanError> {
+        let throttled = Instant::now();
         sleep(Duration::from_micros(self.throttle));
+        let throttle = throttled.elapsed();
         let a = self.filter.get_state();
         if self.verbose {
-            log_filer_pos(a);
+            self.report(&format_filter_pos(a));
         }
-        self.client.send_position(a)
+        self.transport.send_position(a)?;
+        self.latency
+            .record(received.elapsed().saturating_sub(throttle))?;
+        self.score.check(a)
     }
 
     fn update_plot_data(&self, gps: Option<&[T]>) {
@@ -104,7 +260,27 @@ impl Orchestrator {
                 gps,
                 self.filter.get_nis(),
             );
+            self.filter.push_plot_data(&mut plot_data);
+        };
+    }
+
+    /// Fixed-lag estimate, if the filter keeps one, out of the PlotData lock
+    fn emit_lagged(&mut self) -> Result<(), KalmanError> {
+        if self.lag_out.is_none() && self.plot_data.is_none() {
+            return Ok(());
+        }
+        let Some(lagged) = self.filter.lagged_state()? else {
+            return Ok(());
         };
+        if let Some(lag_out) = &mut self.lag_out {
+            write_step(&lagged.smoothed, lag_out)?;
+        }
+        if let Some(plot_data) = &self.plot_data
+            && let Ok(mut plot_data) = plot_data.lock()
+        {
+            plot_data.push_lagged(lagged.smoothed.x.as_slice(), lagged.filtered.as_slice());
+        }
+        Ok(())
     }
 
     fn set_done(&self) {
@@ -115,3 +291,233 @@ impl Orchestrator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Packet;
+    use crate::transport::{ChannelServer, channel_pair};
+    use crate::types::Vector3;
+    use nalgebra::vector;
+    use std::thread::{self, JoinHandle};
+
+    const STEP_MS: u64 = 10;
+
+    fn stamped(millis: u64, message: Message) -> Packet {
+        Packet {
+            timestamp: Some(Timestamp::from_millis(millis)),
+            message,
+        }
+    }
+
+    /// Runs a default orchestrator against the returned server
+    fn spawn(
+        build: impl FnOnce(Orchestrator) -> Orchestrator + Send + 'static,
+    ) -> (ChannelServer, JoinHandle<Result<(), KalmanError>>) {
+        let (transport, server) = channel_pair();
+        let handle = thread::spawn(move || {
+            build(Orchestrator::new(
+                Box::new(transport),
+                None,
+                0,
+                false,
+                false,
+            ))
+            .run()
+        });
+        (server, handle)
+    }
+
+    /// Generation then the first block, speed in km/h along x
+    fn send_init(server: &ChannelServer, pos: Vector3, speed: T) -> Result<(), KalmanError> {
+        server.send(Message::Generation)?;
+        server.send(Message::Start)?;
+        server.send(stamped(0, Message::TruePosition(pos)))?;
+        server.send(stamped(0, Message::Speed(speed)))?;
+        server.send(stamped(0, Message::Acceleration(Vector3::zeros())))?;
+        server.send(stamped(0, Message::Direction(Vector3::zeros())))?;
+        server.send(Message::End)
+    }
+
+    fn send_block(
+        server: &ChannelServer,
+        step: u64,
+        acc: Vector3,
+        extra: Option<Message>,
+    ) -> Result<(), KalmanError> {
+        server.send(Message::Start)?;
+        server.send(stamped(step * STEP_MS, Message::Acceleration(acc)))?;
+        if let Some(message) = extra {
+            server.send(stamped(step * STEP_MS, message))?;
+        }
+        server.send(Message::End)
+    }
+
+    #[test]
+    fn replies_once_per_block() -> Result<(), KalmanError> {
+        let (server, handle) = spawn(|orchestrator| orchestrator);
+        let pos = vector![1., 2., 3.];
+        send_init(&server, pos, 0.)?;
+        assert_eq!(server.recv_position()?, pos);
+        for step in 1..=5 {
+            send_block(
+                &server,
+                step,
+                Vector3::zeros(),
+                Some(Message::Position(pos)),
+            )?;
+            assert!((server.recv_position()? - pos).norm() < 1e-9);
+        }
+        server.send(Message::Goodbye)?;
+        handle.join().expect("orchestrator thread")?;
+        assert_eq!(server.positions().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn integrates_stamped_accelerations() -> Result<(), KalmanError> {
+        let (server, handle) = spawn(|orchestrator| orchestrator);
+        send_init(&server, Vector3::zeros(), 36.)?;
+        server.recv_position()?;
+        let acc = vector![1., 0., 0.];
+        for step in 1..=100 {
+            send_block(&server, step, acc, None)?;
+            let t = (step * STEP_MS) as T / 1000.;
+            let expected = vector![10. * t + 0.5 * t * t, 0., 0.];
+            assert!((server.recv_position()? - expected).norm() < 1e-9);
+        }
+        server.send(Message::Goodbye)?;
+        handle.join().expect("orchestrator thread")
+    }
+
+    #[test]
+    fn writes_the_lagged_estimate_every_step() -> Result<(), KalmanError> {
+        let (lag, steps) = (10, 50);
+        let path = std::env::temp_dir().join(format!("kalman-lag-{}", std::process::id()));
+        let filter = Kalman::new(KalmanConfig::default()).with_fixed_lag(lag);
+        let lag_out = path.clone();
+        let (server, handle) = spawn(move |orchestrator| {
+            orchestrator
+                .with_filter(Box::new(filter))
+                .with_lag_out(&lag_out)
+                .expect("lag output")
+        });
+        let pos = vector![1., 2., 3.];
+        send_init(&server, pos, 0.)?;
+        for step in 1..=steps {
+            let fix = (step % 20 == 0).then_some(Message::Position(pos));
+            send_block(&server, step, Vector3::zeros(), fix)?;
+        }
+        server.send(Message::Goodbye)?;
+        handle.join().expect("orchestrator thread")?;
+        let lines = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(lines.lines().count() as u64, steps + 1 - lag as u64);
+        assert!(lines.lines().all(|line| line.split(' ').count() == 13));
+        Ok(())
+    }
+
+    #[test]
+    fn truth_is_fused_unless_disabled() -> Result<(), KalmanError> {
+        let pos = vector![1., 2., 3.];
+        let moved = vector![5., 2., 3.];
+        let mut replies = Vec::new();
+        for fuse_truth in [None, Some(false)] {
+            let (server, handle) = spawn(move |o| match fuse_truth {
+                Some(fuse_truth) => o.with_truth_fusion(fuse_truth),
+                None => o,
+            });
+            send_init(&server, pos, 0.)?;
+            server.recv_position()?;
+            send_block(
+                &server,
+                1,
+                Vector3::zeros(),
+                Some(Message::TruePosition(moved)),
+            )?;
+            replies.push(server.recv_position()?);
+            server.send(Message::Goodbye)?;
+            handle.join().expect("orchestrator thread")?;
+        }
+        assert!(replies[0].x > pos.x);
+        assert!((replies[1] - pos).norm() < 1e-9);
+        Ok(())
+    }
+
+    /// Only the truths past the initial block are scored, the initial reply
+    /// being the truth itself
+    #[test]
+    fn init_block_is_not_scored() -> Result<(), KalmanError> {
+        let pos = vector![1., 2., 3.];
+        for (truth, scored) in [(None, 0), (Some(Message::TruePosition(pos)), 1)] {
+            let (transport, server) = channel_pair();
+            let handle = thread::spawn(move || {
+                let mut orchestrator =
+                    Orchestrator::new(Box::new(transport), None, 0, false, false);
+                orchestrator.run().map(|()| orchestrator.score.count())
+            });
+            send_init(&server, pos, 0.)?;
+            send_block(&server, 1, Vector3::zeros(), truth)?;
+            server.send(Message::Goodbye)?;
+            assert_eq!(handle.join().expect("orchestrator thread")?, scored);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_init_block_is_an_error() -> Result<(), KalmanError> {
+        let (server, handle) = spawn(|orchestrator| orchestrator);
+        server.send(Message::Generation)?;
+        server.send(Message::Start)?;
+        for _ in 0..5 {
+            server.send(Message::End)?;
+        }
+        let result = handle.join().expect("orchestrator thread");
+        assert!(matches!(result, Err(KalmanError::Parsing(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn init_block_needs_an_acceleration() -> Result<(), KalmanError> {
+        let (server, handle) = spawn(|orchestrator| orchestrator);
+        server.send(Message::Generation)?;
+        server.send(Message::Start)?;
+        server.send(stamped(0, Message::TruePosition(Vector3::zeros())))?;
+        server.send(stamped(0, Message::Speed(0.)))?;
+        server.send(stamped(0, Message::Position(Vector3::zeros())))?;
+        server.send(stamped(0, Message::Direction(Vector3::zeros())))?;
+        server.send(Message::End)?;
+        let result = handle.join().expect("orchestrator thread");
+        assert!(matches!(result, Err(KalmanError::Parsing(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn disconnection_is_an_error() {
+        let (server, handle) = spawn(|orchestrator| orchestrator);
+        drop(server);
+        let result = handle.join().expect("orchestrator thread");
+        assert!(matches!(result, Err(KalmanError::Disconnected)));
+    }
+
+    /// Step 2 is dropped, step 3 repeated and step 2 arrives late: each
+    /// prediction spans the real gap and the stale packets move nothing
+    #[test]
+    fn predicts_over_the_real_gap() -> Result<(), KalmanError> {
+        let (server, handle) = spawn(|orchestrator| orchestrator);
+        send_init(&server, Vector3::zeros(), 0.)?;
+        server.recv_position()?;
+        let acc = vector![1., 0., 0.];
+        for (step, elapsed) in [(1, 1), (3, 3), (3, 3), (2, 3), (4, 4)] {
+            send_block(&server, step, acc, None)?;
+            let t = (elapsed * STEP_MS) as T / 1000.;
+            let expected = vector![0.5 * t * t, 0., 0.];
+            assert!(
+                (server.recv_position()? - expected).norm() < 1e-12,
+                "step {step}"
+            );
+        }
+        server.send(Message::Goodbye)?;
+        handle.join().expect("orchestrator thread")
+    }
+}
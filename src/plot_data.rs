@@ -28,6 +28,25 @@ pub struct PlotData {
 
     pub nis: Vec<T>,
 
+    pub bx: Vec<T>,
+    pub by: Vec<T>,
+    pub bz: Vec<T>,
+
+    pub bx_unc: Vec<T>,
+    pub by_unc: Vec<T>,
+    pub bz_unc: Vec<T>,
+
+    pub x_lag: Vec<T>,
+    pub y_lag: Vec<T>,
+    pub z_lag: Vec<T>,
+
+    pub x_lag_rt: Vec<T>,
+    pub y_lag_rt: Vec<T>,
+    pub z_lag_rt: Vec<T>,
+
+    pub model_names: Vec<&'static str>,
+    pub model_probs: Vec<Vec<T>>,
+
     pub done: bool,
     max_size: usize,
     max_size_gps: usize,
@@ -136,4 +155,90 @@ impl PlotData {
 
         self.nis.push(nis);
     }
+
+    /// Accelerometer bias estimate, only filters estimating it push one
+    pub fn push_bias(&mut self, bias: &[T], bias_unc: &[T]) {
+        if self.bx.len() > self.max_size {
+            let excess = self.bx.len() - self.max_size;
+            self.bx.drain(0..excess);
+            self.by.drain(0..excess);
+            self.bz.drain(0..excess);
+
+            self.bx_unc.drain(0..excess);
+            self.by_unc.drain(0..excess);
+            self.bz_unc.drain(0..excess);
+        }
+
+        self.bx.push(bias[0]);
+        self.by.push(bias[1]);
+        self.bz.push(bias[2]);
+
+        self.bx_unc.push(bias_unc[0]);
+        self.by_unc.push(bias_unc[1]);
+        self.bz_unc.push(bias_unc[2]);
+    }
+
+    /// Fixed-lag smoothed position and the real-time one of the same step,
+    /// only filters keeping a lag push them
+    pub fn push_lagged(&mut self, smoothed: &[T], realtime: &[T]) {
+        if self.x_lag.len() > self.max_size {
+            let excess = self.x_lag.len() - self.max_size;
+            self.x_lag.drain(0..excess);
+            self.y_lag.drain(0..excess);
+            self.z_lag.drain(0..excess);
+
+            self.x_lag_rt.drain(0..excess);
+            self.y_lag_rt.drain(0..excess);
+            self.z_lag_rt.drain(0..excess);
+        }
+
+        self.x_lag.push(smoothed[0]);
+        self.y_lag.push(smoothed[1]);
+        self.z_lag.push(smoothed[2]);
+
+        self.x_lag_rt.push(realtime[0]);
+        self.y_lag_rt.push(realtime[1]);
+        self.z_lag_rt.push(realtime[2]);
+    }
+
+    /// Probability of each model, only multiple model filters push them
+    pub fn push_models(&mut self, names: &[&'static str], probabilities: &[T]) {
+        if self.model_names.is_empty() {
+            self.model_names = names.to_vec();
+            self.model_probs = vec![Vec::with_capacity(self.max_size); names.len()];
+        }
+        for (probs, probability) in self.model_probs.iter_mut().zip(probabilities) {
+            if probs.len() > self.max_size {
+                let excess = probs.len() - self.max_size;
+                probs.drain(0..excess);
+            }
+            probs.push(*probability);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_models_keeps_one_series_per_model() {
+        let mut plot_data = PlotData::new(false);
+        plot_data.push_models(&["Cruise", "Turn"], &[0.75, 0.25]);
+        plot_data.push_models(&["Cruise", "Turn"], &[0.5, 0.5]);
+        assert_eq!(plot_data.model_names, ["Cruise", "Turn"]);
+        assert_eq!(plot_data.model_probs, [[0.75, 0.5], [0.25, 0.5]]);
+    }
+
+    #[test]
+    fn push_models_drops_the_oldest_beyond_max_size() {
+        let mut plot_data = PlotData::new(false);
+        let max_size = plot_data.max_size;
+        for i in 0..2 * max_size {
+            plot_data.push_models(&["Cruise"], &[i as T]);
+        }
+        let probs = &plot_data.model_probs[0];
+        assert!(probs.len() <= max_size + 1);
+        assert_eq!(probs.last(), Some(&((2 * max_size - 1) as T)));
+    }
 }
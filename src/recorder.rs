@@ -0,0 +1,170 @@
+//! Session recording format, all integers little endian:
+//!
+//! ```text
+//! file   := "KREC" version:u8 record*
+//! record := direction:u8 seq:u32 time:u64 len:u16 data:[u8; len]
+//! ```
+//!
+//! `direction` is 0 for a datagram received from the simulator and 1 for a
+//! position sent back, `seq` counts records from 0 in both directions, `time`
+//! is nanoseconds since the recording started (monotonic clock) and `data`
+//! is the raw datagram.
+
+use crate::error::KalmanError;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const MAGIC: &[u8; 4] = b"KREC";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received = 0,
+    Sent = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    pub seq: u32,
+    pub time: Duration,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    output: BufWriter<File>,
+    start: Instant,
+    seq: u32,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder, KalmanError> {
+        let mut output = BufWriter::new(File::create(path)?);
+        output.write_all(MAGIC)?;
+        output.write_all(&[VERSION])?;
+        Ok(Recorder {
+            output,
+            start: Instant::now(),
+            seq: 0,
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> Result<(), KalmanError> {
+        let len = u16::try_from(data.len()).map_err(|_| KalmanError::MessageTooLong(data.len()))?;
+        let time = self.start.elapsed().as_nanos() as u64;
+        self.output.write_all(&[direction as u8])?;
+        self.output.write_all(&self.seq.to_le_bytes())?;
+        self.output.write_all(&time.to_le_bytes())?;
+        self.output.write_all(&len.to_le_bytes())?;
+        self.output.write_all(data)?;
+        self.seq = self.seq.wrapping_add(1);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), KalmanError> {
+        Ok(self.output.flush()?)
+    }
+}
+
+/// Iterator over the records of a recording.
+#[derive(Debug)]
+pub struct Recording<R: Read> {
+    input: R,
+}
+
+impl Recording<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, KalmanError> {
+        Recording::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Recording<R> {
+    pub fn new(mut input: R) -> Result<Self, KalmanError> {
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(KalmanError::Parsing("Not a version 1 recording".into()));
+        }
+        Ok(Recording { input })
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>, KalmanError> {
+        let mut direction = [0; 1];
+        match self.input.read_exact(&mut direction) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        let direction = match direction[0] {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            d => return Err(KalmanError::Parsing(format!("Bad record direction {d}"))),
+        };
+        let mut seq = [0; 4];
+        let mut time = [0; 8];
+        let mut len = [0; 2];
+        self.input.read_exact(&mut seq)?;
+        self.input.read_exact(&mut time)?;
+        self.input.read_exact(&mut len)?;
+        let mut data = vec![0; u16::from_le_bytes(len) as usize];
+        self.input.read_exact(&mut data)?;
+        Ok(Some(Record {
+            direction,
+            seq: u32::from_le_bytes(seq),
+            time: Duration::from_nanos(u64::from_le_bytes(time)),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Recording<R> {
+    type Item = Result<Record, KalmanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_read_back() -> Result<(), KalmanError> {
+        let path = std::env::temp_dir().join(format!("kalman-record-{}.krec", std::process::id()));
+        let datagrams = [
+            (Direction::Received, &b"MSG_START"[..]),
+            (Direction::Received, b"[00:00:00.010]ACCELERATION\n1\n2\n3"),
+            (Direction::Sent, b"1 2 3"),
+            (Direction::Received, b""),
+        ];
+        let mut recorder = Recorder::create(&path)?;
+        for (direction, data) in datagrams {
+            recorder.record(direction, data)?;
+        }
+        recorder.flush()?;
+        let records = Recording::open(&path)?.collect::<Result<Vec<_>, _>>();
+        std::fs::remove_file(&path)?;
+        let records = records?;
+
+        assert_eq!(records.len(), datagrams.len());
+        for (seq, (record, (direction, data))) in records.iter().zip(datagrams).enumerate() {
+            assert_eq!(record.seq, seq as u32);
+            assert_eq!(record.direction, direction);
+            assert_eq!(record.data, data);
+        }
+        assert!(records.windows(2).all(|pair| pair[0].time <= pair[1].time));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        for header in [&b"KREX\x01"[..], b"KREC\x02", b"KRE"] {
+            assert!(Recording::new(header).is_err(), "{header:?}");
+        }
+        let wrong_magic = Recording::new(&b"KREX\x01"[..]);
+        assert!(matches!(wrong_magic, Err(KalmanError::Parsing(_))));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::error::KalmanError;
+use crate::message::{Message, Packet};
+use crate::recorder::{Direction, Recording};
+use crate::transport::Transport;
+use crate::types::T;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Feeds the orchestrator from a session recording instead of the network.
+pub struct ReplayTransport {
+    recording: Recording<BufReader<File>>,
+    output: BufWriter<Box<dyn Write + Send>>,
+    stdout: bool,
+    realtime: bool,
+    start: Option<(Instant, Duration)>,
+}
+
+impl ReplayTransport {
+    /// Estimated positions go to `output`, stdout if `None`, one `x y z` line
+    /// per reply. `realtime` paces the datagrams as they were recorded,
+    /// otherwise they are replayed as fast as possible.
+    pub fn open(
+        recording: &Path,
+        output: Option<&Path>,
+        realtime: bool,
+    ) -> Result<ReplayTransport, KalmanError> {
+        let stdout = output.is_none();
+        let output: Box<dyn Write + Send> = match output {
+            Some(path) => Box::new(File::create(path)?),
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(ReplayTransport {
+            recording: Recording::open(recording)?,
+            output: BufWriter::new(output),
+            stdout,
+            realtime,
+            start: None,
+        })
+    }
+
+    fn pace(&mut self, time: Duration) {
+        let (start, origin) = *self.start.get_or_insert((Instant::now(), time));
+        let due = time.saturating_sub(origin);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            sleep(wait);
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn start(&mut self) -> Result<(), KalmanError> {
+        loop {
+            match self.recv()?.message {
+                Message::Generation => return Ok(()),
+                Message::Goodbye => {
+                    return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A recording that ends between two records, without the GOODBYE of a
+    /// session cut short, ends as if it had one
+    fn recv(&mut self) -> Result<Packet, KalmanError> {
+        loop {
+            let Some(record) = self.recording.next().transpose()? else {
+                return Ok(Message::Goodbye.into());
+            };
+            if record.direction != Direction::Received {
+                continue;
+            }
+            if self.realtime {
+                self.pace(record.time);
+            }
+            return Packet::parse(&record.data);
+        }
+    }
+
+    fn send_position(&mut self, pos: &[T]) -> Result<(), KalmanError> {
+        writeln!(self.output, "{} {} {}", pos[0], pos[1], pos[2])?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KalmanError> {
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn uses_stdout(&self) -> bool {
+        self.stdout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::Orchestrator;
+    use crate::recorder::Recorder;
+
+    fn record(recorder: &mut Recorder, datagram: &str) -> Result<(), KalmanError> {
+        recorder.record(Direction::Received, datagram.as_bytes())
+    }
+
+    /// A session at 10 m/s along x whose GOODBYE never came, 4 replies
+    /// long
+    fn write_session(path: &Path) -> Result<(), KalmanError> {
+        let mut recorder = Recorder::create(path)?;
+        record(&mut recorder, "Trajectory Generated!\nSending Info. . .\n")?;
+        record(&mut recorder, "MSG_START")?;
+        record(&mut recorder, "[00:00:00.000]TRUE POSITION\n1\n2\n3")?;
+        record(&mut recorder, "[00:00:00.000]SPEED\n36")?;
+        record(&mut recorder, "[00:00:00.000]ACCELERATION\n0\n0\n0")?;
+        record(&mut recorder, "[00:00:00.000]DIRECTION\n0\n0\n0")?;
+        record(&mut recorder, "MSG_END")?;
+        for step in 1..=3 {
+            record(&mut recorder, "MSG_START")?;
+            let acceleration = format!("[00:00:00.0{step}0]ACCELERATION\n0\n0\n0");
+            record(&mut recorder, &acceleration)?;
+            record(&mut recorder, "MSG_END")?;
+        }
+        recorder.flush()
+    }
+
+    /// Replays `path`, returning the result and the positions written
+    fn replay(path: &Path) -> (Result<(), KalmanError>, String) {
+        let output = path.with_extension("txt");
+        let result = ReplayTransport::open(path, Some(&output), false).and_then(|transport| {
+            Orchestrator::new(Box::new(transport), None, 0, false, false).run()
+        });
+        let positions = std::fs::read_to_string(&output).unwrap_or_default();
+        let _ = std::fs::remove_file(&output);
+        let _ = std::fs::remove_file(path);
+        (result, positions)
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("kalman-{name}-{}.krec", std::process::id()))
+    }
+
+    #[test]
+    fn clean_end_of_recording_ends_the_session() -> Result<(), KalmanError> {
+        let path = temp_path("replay-end");
+        write_session(&path)?;
+        let (result, positions) = replay(&path);
+        result?;
+        // The initial position, then one prediction a reply
+        let xs: Vec<T> = positions
+            .lines()
+            .map(|line| line.split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(xs.len(), 4);
+        for (step, x) in xs.iter().enumerate() {
+            assert!((x - (1. + 0.1 * step as T)).abs() < 1e-9, "{positions}");
+        }
+        assert!(positions.lines().all(|line| line.ends_with(" 2 3")));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_record_is_an_error() -> Result<(), KalmanError> {
+        let path = temp_path("replay-truncated");
+        write_session(&path)?;
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[Direction::Received as u8, 0, 0])?;
+        drop(file);
+        let (result, _) = replay(&path);
+        assert!(matches!(result, Err(KalmanError::Io(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use crate::types::T;
+use std::f64::consts::TAU;
+
+/// Small seedable PRNG (xoshiro256++ seeded through splitmix64).
+/// Same seed, same sequence, on every platform.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    s: [u64; 4],
+    spare: Option<T>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut sm = seed;
+        let mut next = || {
+            sm = sm.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = sm;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        Rng {
+            s: [next(), next(), next(), next()],
+            spare: None,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.s;
+        let result = (s[0].wrapping_add(s[3])).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// Uniform in [0, 1)
+    pub fn uniform(&mut self) -> T {
+        (self.next_u64() >> 11) as T * (1. / (1u64 << 53) as T)
+    }
+
+    /// Uniform in [low, high)
+    pub fn range(&mut self, low: T, high: T) -> T {
+        low + (high - low) * self.uniform()
+    }
+
+    /// Standard normal sample (Box-Muller)
+    pub fn gaussian(&mut self) -> T {
+        if let Some(spare) = self.spare.take() {
+            return spare;
+        }
+        let u1 = 1. - self.uniform();
+        let u2 = self.uniform();
+        let r = (-2. * u1.ln()).sqrt();
+        self.spare = Some(r * (TAU * u2).sin());
+        r * (TAU * u2).cos()
+    }
+
+    pub fn normal(&mut self, sigma: T) -> T {
+        sigma * self.gaussian()
+    }
+}
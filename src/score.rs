@@ -0,0 +1,131 @@
+use crate::error::KalmanError;
+use crate::types::{T, Vector3};
+use nalgebra::vector;
+use std::fmt;
+
+/// 3D error of the sent positions against the true positions of the same block.
+#[derive(Debug, Default)]
+pub struct Score {
+    truth: Option<Vector3>,
+    max_delta: Option<T>,
+    count: u64,
+    sum: T,
+    sum_sq: T,
+    max: T,
+}
+
+impl Score {
+    pub fn new(max_delta: Option<T>) -> Score {
+        Score {
+            max_delta,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_truth(&mut self, pos: &Vector3) {
+        self.truth = Some(*pos);
+    }
+
+    /// Score `pos` if a true position was received since the last call.
+    pub fn check(&mut self, pos: &[T]) -> Result<(), KalmanError> {
+        let Some(truth) = self.truth.take() else {
+            return Ok(());
+        };
+        let delta = (vector![pos[0], pos[1], pos[2]] - truth).norm();
+        self.count += 1;
+        self.sum += delta;
+        self.sum_sq += delta * delta;
+        self.max = self.max.max(delta);
+        match self.max_delta {
+            Some(max_delta) if delta > max_delta => Err(KalmanError::DeltaTooHigh(delta)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> T {
+        self.max
+    }
+
+    pub fn mean(&self) -> T {
+        if self.count == 0 {
+            return 0.;
+        }
+        self.sum / self.count as T
+    }
+
+    pub fn rms(&self) -> T {
+        if self.count == 0 {
+            return 0.;
+        }
+        (self.sum_sq / self.count as T).sqrt()
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Position error over {} steps: max {:.4} m, mean {:.4} m, rms {:.4} m",
+            self.count,
+            self.max,
+            self.mean(),
+            self.rms()
+        )?;
+        if let Some(max_delta) = self.max_delta {
+            write!(
+                f,
+                " ({:.1}% of the {max_delta} m limit)",
+                100. * self.max / max_delta
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scores a position at `delta` m along x from the origin
+    fn check(score: &mut Score, delta: T) -> Result<(), KalmanError> {
+        score.set_truth(&Vector3::zeros());
+        score.check(&[delta, 0., 0., 0., 0., 0.])
+    }
+
+    #[test]
+    fn tracks_max_mean_and_rms() -> Result<(), KalmanError> {
+        let mut score = Score::new(None);
+        assert_eq!((score.max(), score.mean(), score.rms()), (0., 0., 0.));
+        for delta in [1., 2., 3., 6.] {
+            check(&mut score, delta)?;
+        }
+        assert_eq!(score.count(), 4);
+        assert_eq!(score.max(), 6.);
+        assert_eq!(score.mean(), 3.);
+        assert_eq!(score.rms(), 12.5_f64.sqrt());
+        Ok(())
+    }
+
+    #[test]
+    fn positions_without_truth_are_not_scored() -> Result<(), KalmanError> {
+        let mut score = Score::new(Some(1.));
+        check(&mut score, 0.5)?;
+        score.check(&[100., 0., 0.])?;
+        assert_eq!(score.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn aborts_past_max_delta() -> Result<(), KalmanError> {
+        let mut score = Score::new(Some(2.));
+        check(&mut score, 2.)?;
+        let result = check(&mut score, 2.5);
+        assert!(matches!(result, Err(KalmanError::DeltaTooHigh(delta)) if delta == 2.5));
+        assert_eq!(score.max(), 2.5);
+        Ok(())
+    }
+}
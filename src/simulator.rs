@@ -0,0 +1,232 @@
+use crate::client::{DEFAULT_PORT, MAX_LEN};
+use crate::error::KalmanError;
+use crate::message::{Message, Timestamp};
+use crate::rng::Rng;
+use crate::trajectory::{DT, Trajectory, TrajectoryPoint};
+use crate::types::{T, Vector3};
+use nalgebra::vector;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const GPS_PERIOD: u64 = 300;
+const S_DIR: T = 1e-2;
+const MAX_DELTA: T = 5.;
+const PORT_SEARCH: u16 = 64;
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(1);
+const NOISE_SEED: u64 = 0x5eed;
+const BIAS_SEED: u64 = 0xb1a5;
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub port: u16,
+    pub seed: Option<u64>,
+    pub duration: u64, // minutes
+    pub acc_sigma: T,
+    pub gps_sigma: T,
+    pub noise: T,
+    pub debug: bool,
+    pub delta: bool,
+    pub filter_speed: bool,
+    /// ACCELERATION in the vehicle frame instead of the world frame
+    pub body_frame: bool,
+    /// Accelerometer turn-on bias sigma, the bias then drifts by as much
+    /// per sqrt(minute)
+    pub acc_bias: T,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        SimulatorConfig {
+            port: DEFAULT_PORT,
+            seed: None,
+            duration: 90,
+            acc_sigma: 1e-3,
+            gps_sigma: 1e-1,
+            noise: 1.,
+            debug: false,
+            delta: false,
+            filter_speed: false,
+            body_frame: false,
+            acc_bias: 0.,
+        }
+    }
+}
+
+/// Server side of the IMU stream protocol.
+pub struct Simulator {
+    socket: UdpSocket,
+    config: SimulatorConfig,
+    seed: u64,
+    rng: Rng,
+    bias_rng: Rng,
+    bias: Vector3,
+    buf: [u8; MAX_LEN],
+    response_time: Duration,
+    responses: u32,
+}
+
+impl Simulator {
+    pub fn new(config: SimulatorConfig) -> Result<Simulator, KalmanError> {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        println!("Seed: {seed}");
+        let mut bias_rng = Rng::new(seed ^ BIAS_SEED);
+        let bias = Vector3::from_fn(|_, _| bias_rng.normal(config.acc_bias));
+        Ok(Simulator {
+            socket: Self::bind_closest(config.port)?,
+            seed,
+            rng: Rng::new(seed ^ NOISE_SEED),
+            bias_rng,
+            bias,
+            config,
+            buf: [0; MAX_LEN],
+            response_time: Duration::ZERO,
+            responses: 0,
+        })
+    }
+
+    /// Bind `port`, falling back to the closest open port.
+    fn bind_closest(port: u16) -> Result<UdpSocket, KalmanError> {
+        let mut last_err = None;
+        for offset in 0..=PORT_SEARCH {
+            for candidate in [port.checked_add(offset), port.checked_sub(offset)] {
+                let Some(candidate) = candidate else {
+                    continue;
+                };
+                match UdpSocket::bind(("127.0.0.1", candidate)) {
+                    Ok(socket) => return Ok(socket),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| std::io::Error::other("Could not bind!"))
+            .into())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, KalmanError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn run(&mut self) -> Result<(), KalmanError> {
+        println!("Listening on {}", self.local_addr()?);
+        println!("Waiting for READY");
+        let client = self.wait_ready()?;
+        self.socket.set_read_timeout(Some(RECEIPT_TIMEOUT))?;
+        let start = Timestamp::default();
+        self.send(client, Message::Generation, start)?;
+
+        let mut trajectory = Trajectory::new(self.seed, self.config.duration);
+        if let Some(init) = trajectory.next() {
+            self.send(client, Message::Start, start)?;
+            for message in init.messages() {
+                self.send(client, message, start)?;
+            }
+            self.send(client, Message::End, start)?;
+            self.check_reply(client, &init.position)?;
+        }
+
+        for point in trajectory {
+            self.send_step(client, &point)?;
+            self.check_reply(client, &point.position)?;
+        }
+
+        self.send(client, Message::Goodbye, start)?;
+        if self.config.filter_speed && self.responses > 0 {
+            println!(
+                "Mean filter response time : {:?}",
+                self.response_time / self.responses
+            );
+        }
+        Ok(())
+    }
+
+    fn wait_ready(&mut self) -> Result<SocketAddr, KalmanError> {
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut self.buf)?;
+            if &self.buf[..len] == b"READY" {
+                return Ok(addr);
+            }
+        }
+    }
+
+    fn send_step(
+        &mut self,
+        client: SocketAddr,
+        point: &TrajectoryPoint,
+    ) -> Result<(), KalmanError> {
+        let noise = self.config.noise;
+        let acc_sigma = self.config.acc_sigma * noise;
+        let gps_sigma = self.config.gps_sigma * noise;
+        let time = Timestamp::from_millis(point.step * 10);
+
+        // Acceleration first: it brings the filter to this step's positions
+        self.send(client, Message::Start, time)?;
+        let acc = if self.config.body_frame {
+            point.body_acceleration()
+        } else {
+            point.acceleration
+        };
+        let drift = self.config.acc_bias * (DT / 60.).sqrt();
+        self.bias = self.bias.map(|b| b + self.bias_rng.normal(drift));
+        let acc = (acc + self.bias).map(|v| v + self.rng.normal(acc_sigma));
+        self.send(client, Message::Acceleration(acc), time)?;
+        let dir = point.direction.map(|v| v + self.rng.normal(S_DIR));
+        self.send(client, Message::Direction(dir), time)?;
+        if self.config.debug {
+            self.send(client, Message::TruePosition(point.position), time)?;
+        }
+        if point.step.is_multiple_of(GPS_PERIOD) {
+            let gps = point.position.map(|v| v + self.rng.normal(gps_sigma));
+            self.send(client, Message::Position(gps), time)?;
+        }
+        self.send(client, Message::End, time)
+    }
+
+    /// Datagrams from other addresses than `client` are dropped
+    fn check_reply(&mut self, client: SocketAddr, truth: &Vector3) -> Result<(), KalmanError> {
+        let sent = Instant::now();
+        let len = loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, from)) if from == client => break len,
+                Ok(_) => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(KalmanError::TimeoutPosReceipt);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.response_time += sent.elapsed();
+        self.responses += 1;
+
+        let reply = str::from_utf8(&self.buf[..len])?;
+        let mut it = reply.split_whitespace().map(str::parse::<T>);
+        let pos = match (it.next(), it.next(), it.next(), it.next()) {
+            (Some(Ok(x)), Some(Ok(y)), Some(Ok(z)), None) => vector![x, y, z],
+            _ => return Err(KalmanError::IncorrectDataInput(reply.into())),
+        };
+        let delta = (pos - truth).norm();
+        if self.config.delta {
+            println!("Delta: {delta:.4}");
+        }
+        if delta > MAX_DELTA {
+            return Err(KalmanError::DeltaTooHigh(delta));
+        }
+        Ok(())
+    }
+
+    fn send(
+        &self,
+        client: SocketAddr,
+        message: Message,
+        timestamp: Timestamp,
+    ) -> Result<(), KalmanError> {
+        self.socket.send_to(&message.encode(timestamp), client)?;
+        Ok(())
+    }
+}
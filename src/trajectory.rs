@@ -0,0 +1,194 @@
+use crate::message::Message;
+use crate::rng::Rng;
+use crate::types::{T, Vector3};
+use nalgebra::{Rotation3, vector};
+use std::f64::consts::PI;
+
+/// Generation step, the filter's 100 Hz rate
+pub const DT: T = 0.01;
+pub const STEPS_PER_MINUTE: u64 = 60 * 100;
+
+const MS_TO_KMH: T = 3600. / 1000.;
+const SEGMENT: u64 = 1000;
+const MIN_SPEED: T = 5.;
+const MAX_SPEED: T = 40.;
+const MAX_PITCH: T = 0.2;
+
+/// Ground truth at one step, the quantities carried by `Message`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrajectoryPoint {
+    pub step: u64,
+    pub position: Vector3,
+    pub velocity: Vector3,
+    /// Navigation frame acceleration over the step that led to this point
+    pub acceleration: Vector3,
+    /// Euler angles (roll, pitch, yaw)
+    pub direction: Vector3,
+}
+
+impl TrajectoryPoint {
+    pub fn time(&self) -> T {
+        self.step as T * DT
+    }
+
+    /// Speed in km/h, as sent in SPEED
+    pub fn speed(&self) -> T {
+        self.velocity.norm() * MS_TO_KMH
+    }
+
+    pub fn rotation(&self) -> Rotation3<T> {
+        Rotation3::from_euler_angles(self.direction.x, self.direction.y, self.direction.z)
+    }
+
+    /// Acceleration expressed in the vehicle frame
+    pub fn body_acceleration(&self) -> Vector3 {
+        self.rotation().inverse() * self.acceleration
+    }
+
+    /// Noiseless init block content, in the order the simulator sends it.
+    pub fn messages(&self) -> [Message; 4] {
+        [
+            Message::TruePosition(self.position),
+            Message::Speed(self.speed()),
+            Message::Acceleration(self.acceleration),
+            Message::Direction(self.direction),
+        ]
+    }
+}
+
+/// Deterministic vehicle path: same seed and duration, bit-identical points.
+#[derive(Debug, Clone)]
+pub struct Trajectory {
+    rng: Rng,
+    step: u64,
+    steps: u64,
+    position: Vector3,
+    velocity: Vector3,
+    acceleration: Vector3,
+    speed_rate: T,
+    yaw_rate: T,
+    pitch_rate: T,
+}
+
+impl Trajectory {
+    /// `duration` in minutes
+    pub fn new(seed: u64, duration: u64) -> Trajectory {
+        let mut rng = Rng::new(seed);
+        let yaw = rng.range(-PI, PI);
+        let speed = rng.range(20., 120.) / MS_TO_KMH;
+        Trajectory {
+            position: vector![
+                rng.range(-1e3, 1e3),
+                rng.range(-1e3, 1e3),
+                rng.range(0., 100.)
+            ],
+            velocity: Rotation3::from_euler_angles(0., 0., yaw) * vector![speed, 0., 0.],
+            acceleration: Vector3::zeros(),
+            rng,
+            step: 0,
+            steps: duration * STEPS_PER_MINUTE,
+            speed_rate: 0.,
+            yaw_rate: 0.,
+            pitch_rate: 0.,
+        }
+    }
+
+    /// Number of steps after the initial point
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn direction(velocity: &Vector3) -> Vector3 {
+        let horizontal = velocity.x.hypot(velocity.y);
+        vector![
+            0.,
+            (-velocity.z).atan2(horizontal),
+            velocity.y.atan2(velocity.x)
+        ]
+    }
+
+    /// Pick a new manoeuvre: cruise, brake/accelerate, turn
+    fn next_segment(&mut self) {
+        let (speed_rate, yaw_rate) = match self.rng.next_u64() % 3 {
+            0 => (0., 0.),
+            1 => (self.rng.range(-1.5, 1.5), 0.),
+            _ => (self.rng.range(-0.3, 0.3), self.rng.range(-0.1, 0.1)),
+        };
+        self.speed_rate = speed_rate;
+        self.yaw_rate = yaw_rate;
+        self.pitch_rate = self.rng.range(-0.002, 0.002);
+    }
+
+    fn next_velocity(&self) -> Vector3 {
+        let speed = self.velocity.norm();
+        let speed_rate = match speed {
+            s if s < MIN_SPEED => self.speed_rate.abs(),
+            s if s > MAX_SPEED => -self.speed_rate.abs(),
+            _ => self.speed_rate,
+        };
+        let dir = Self::direction(&self.velocity);
+        let pitch = (dir.y + self.pitch_rate * DT).clamp(-MAX_PITCH, MAX_PITCH);
+        let rot = Rotation3::from_euler_angles(0., pitch, dir.z + self.yaw_rate * DT);
+        rot * vector![speed + speed_rate * DT, 0., 0.]
+    }
+}
+
+impl Iterator for Trajectory {
+    type Item = TrajectoryPoint;
+
+    fn next(&mut self) -> Option<TrajectoryPoint> {
+        if self.step > self.steps {
+            return None;
+        }
+        let point = TrajectoryPoint {
+            step: self.step,
+            position: self.position,
+            velocity: self.velocity,
+            acceleration: self.acceleration,
+            direction: Self::direction(&self.velocity),
+        };
+
+        if self.step.is_multiple_of(SEGMENT) {
+            self.next_segment();
+        }
+        let next_velocity = self.next_velocity();
+        self.acceleration = (next_velocity - self.velocity) / DT;
+        self.position += self.velocity * DT + 0.5 * self.acceleration * DT * DT;
+        self.velocity = next_velocity;
+        self.step += 1;
+        Some(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every float of the trajectory, bit for bit
+    fn bits(seed: u64) -> Vec<u64> {
+        Trajectory::new(seed, 1)
+            .flat_map(|point| {
+                [
+                    point.position,
+                    point.velocity,
+                    point.acceleration,
+                    point.direction,
+                ]
+            })
+            .flat_map(|v| [v.x, v.y, v.z])
+            .map(T::to_bits)
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_is_bit_identical() {
+        let first = bits(42);
+        assert_eq!(first.len(), 12 * (STEPS_PER_MINUTE as usize + 1));
+        assert_eq!(first, bits(42));
+    }
+
+    #[test]
+    fn different_seeds_differ() {
+        assert_ne!(bits(1), bits(2));
+    }
+}
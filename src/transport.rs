@@ -0,0 +1,185 @@
+use crate::error::KalmanError;
+use crate::message::{Message, Packet};
+use crate::types::{T, Vector3};
+use nalgebra::vector;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Lines, Write};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+/// Where the orchestrator gets its messages from and sends its positions to.
+pub trait Transport {
+    /// Handshake, returns once the trajectory is generated.
+    fn start(&mut self) -> Result<(), KalmanError>;
+    fn recv(&mut self) -> Result<Packet, KalmanError>;
+    fn send_position(&mut self, pos: &[T]) -> Result<(), KalmanError>;
+
+    /// Pushes out any buffered output, at the end of the session or on an
+    /// error.
+    fn flush(&mut self) -> Result<(), KalmanError> {
+        Ok(())
+    }
+
+    /// Whether the positions are written to stdout, the summaries then
+    /// going to stderr.
+    fn uses_stdout(&self) -> bool {
+        false
+    }
+}
+
+/// In-memory transport, the other end is a `ChannelServer`.
+pub struct ChannelTransport {
+    messages: Receiver<Packet>,
+    positions: Sender<Vector3>,
+}
+
+/// Server side of a `ChannelTransport`.
+pub struct ChannelServer {
+    messages: Sender<Packet>,
+    positions: Receiver<Vector3>,
+}
+
+pub fn channel_pair() -> (ChannelTransport, ChannelServer) {
+    let (msg_tx, msg_rx) = channel();
+    let (pos_tx, pos_rx) = channel();
+    (
+        ChannelTransport {
+            messages: msg_rx,
+            positions: pos_tx,
+        },
+        ChannelServer {
+            messages: msg_tx,
+            positions: pos_rx,
+        },
+    )
+}
+
+impl Transport for ChannelTransport {
+    fn start(&mut self) -> Result<(), KalmanError> {
+        while !matches!(self.recv()?.message, Message::Generation) {}
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Packet, KalmanError> {
+        self.messages.recv().map_err(|_| KalmanError::Disconnected)
+    }
+
+    fn send_position(&mut self, pos: &[T]) -> Result<(), KalmanError> {
+        self.positions
+            .send(vector![pos[0], pos[1], pos[2]])
+            .map_err(|_| KalmanError::Disconnected)
+    }
+}
+
+impl ChannelServer {
+    pub fn send(&self, packet: impl Into<Packet>) -> Result<(), KalmanError> {
+        self.messages
+            .send(packet.into())
+            .map_err(|_| KalmanError::Disconnected)
+    }
+
+    pub fn recv_position(&self) -> Result<Vector3, KalmanError> {
+        self.positions.recv().map_err(|_| KalmanError::Disconnected)
+    }
+
+    /// Positions already sent by the filter, without blocking
+    pub fn positions(&self) -> impl Iterator<Item = Vector3> + '_ {
+        self.positions.try_iter()
+    }
+}
+
+/// Datagrams read from a text file, one per line, with `\n` standing for the
+/// newlines inside a datagram and `\\` for a backslash:
+///
+/// ```text
+/// Trajectory Generated!\nSending Info. . .\n
+/// MSG_START
+/// [00:00:00.000]TRUE POSITION\n1.5\n2\n0
+/// ```
+///
+/// Positions are written to `output`, one `x y z` line per reply.
+pub struct FileTransport<R: BufRead, W: Write> {
+    lines: Lines<R>,
+    output: Option<W>,
+}
+
+impl FileTransport<BufReader<File>, File> {
+    pub fn open(input: &Path, output: Option<&Path>) -> Result<Self, KalmanError> {
+        Ok(FileTransport::new(
+            BufReader::new(File::open(input)?),
+            output.map(File::create).transpose()?,
+        ))
+    }
+}
+
+impl<R: BufRead, W: Write> FileTransport<R, W> {
+    pub fn new(input: R, output: Option<W>) -> Self {
+        FileTransport {
+            lines: input.lines(),
+            output,
+        }
+    }
+
+    fn unescape(line: &str) -> String {
+        let mut datagram = String::with_capacity(line.len());
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => datagram.push('\n'),
+                ('\\', Some('\\')) => datagram.push('\\'),
+                _ => {
+                    datagram.push(c);
+                    continue;
+                }
+            }
+            chars.next();
+        }
+        datagram
+    }
+}
+
+impl<R: BufRead, W: Write> Transport for FileTransport<R, W> {
+    fn start(&mut self) -> Result<(), KalmanError> {
+        while !matches!(self.recv()?.message, Message::Generation) {}
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Packet, KalmanError> {
+        let line = self
+            .lines
+            .next()
+            .ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))??;
+        Packet::parse(Self::unescape(&line).as_bytes())
+    }
+
+    fn send_position(&mut self, pos: &[T]) -> Result<(), KalmanError> {
+        if let Some(output) = &mut self.output {
+            writeln!(output, "{} {} {}", pos[0], pos[1], pos[2])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn file_transport_reads_escaped_datagrams() -> Result<(), KalmanError> {
+        let input = "MSG_START\nTrajectory Generated!\\nSending Info. . .\\n\n\
+                     [00:00:01.500]TRUE POSITION\\n1.5\\n2\\n0\nMSG_END\n";
+        let mut output = Vec::new();
+        let mut transport = FileTransport::new(Cursor::new(input), Some(&mut output));
+        transport.start()?;
+        assert_eq!(
+            transport.recv()?.message,
+            Message::TruePosition(vector![1.5, 2., 0.])
+        );
+        assert_eq!(transport.recv()?.message, Message::End);
+        assert!(matches!(transport.recv(), Err(KalmanError::Io(_))));
+        transport.send_position(&[1., 2.5, -3.])?;
+        assert_eq!(String::from_utf8_lossy(&output), "1 2.5 -3\n");
+        Ok(())
+    }
+}